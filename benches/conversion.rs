@@ -0,0 +1,78 @@
+// Conversion throughput of the typst2latex binary on generated documents, run with `cargo bench`.
+// Pandoc is replaced by a stub echoing its input, so that the measures focus on the parsing and
+// the handling of the pandoc calls. Set TYPST2LATEX_BENCH_PANDOC=real to use the installed pandoc.
+
+use criterion::{Criterion, criterion_group, criterion_main};
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+/// Many short paragraphs, each converted by its own pandoc call.
+fn paragraphs() -> String {
+    let mut doc = String::from("= Introduction\n\n");
+    for i in 0..500 {
+        doc += &format!(
+            "Paragraph {i} with *strong* text, a reference @sec{i} and #text(baseline: 1pt)[shifted words].\n\n"
+        );
+    }
+    doc
+}
+
+/// Equations using the math functions converted before pandoc.
+fn math() -> String {
+    let mut doc = String::from("= Results\n\n");
+    for i in 0..300 {
+        doc += &format!(
+            "We have $floor(x_{i} / 2) + ceil(y^{i})$ and\n$ mat(1, {i}; {i}, 1, augment: 1) vec(a, b) = sum_(k=0)^{i} k $\n\n"
+        );
+    }
+    doc
+}
+
+/// The PATH of the conversions, with the stub pandoc of `dir` first unless the real one is asked.
+fn pandoc_path(dir: &Path) -> String {
+    let path = env::var("PATH").unwrap_or_default();
+    if env::var("TYPST2LATEX_BENCH_PANDOC").as_deref() == Ok("real") {
+        return path;
+    }
+    let stub = dir.join("pandoc");
+    fs::write(&stub, "#!/bin/sh\ncat\n").unwrap();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&stub, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+    format!("{}:{}", dir.display(), path)
+}
+
+/// Converts `input` with the empty template.
+fn convert(input: &Path, path_var: &str) {
+    let status = Command::new(env!("CARGO_BIN_EXE_typst2latex"))
+        .arg(input)
+        .args(["-t", "empty"])
+        .env("PATH", path_var)
+        .status()
+        .expect("failed to run typst2latex");
+    assert!(status.success(), "conversion of {:?} failed", input);
+}
+
+fn conversion(c: &mut Criterion) {
+    let dir = env::temp_dir().join(format!("typst2latex-bench-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path_var = pandoc_path(&dir);
+
+    let mut group = c.benchmark_group("conversion");
+    group.sample_size(10);
+    for (name, doc) in [("paragraphs", paragraphs()), ("math", math())] {
+        let input = dir.join(format!("{name}.typ"));
+        fs::write(&input, doc).unwrap();
+        group.bench_function(name, |b| b.iter(|| convert(&input, &path_var)));
+    }
+    group.finish();
+
+    fs::remove_dir_all(&dir).ok();
+}
+
+criterion_group!(benches, conversion);
+criterion_main!(benches);
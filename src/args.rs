@@ -0,0 +1,210 @@
+use crate::converter::Rule;
+use pest::iterators::Pairs;
+use std::fmt;
+
+/// Arguments of a typst function call or set rule, with values as written in the source.
+#[derive(Debug, Clone, Default)]
+pub struct Args {
+    pub positional: Vec<String>,
+    pub named: Vec<(String, String)>,
+}
+
+impl Args {
+    /// Collects the `arg` pairs among `pairs`.
+    pub fn from_pairs(pairs: Pairs<Rule>) -> Self {
+        let mut args = Args::default();
+        for arg in pairs.filter(|p| p.as_rule() == Rule::arg) {
+            let mut name = None;
+            let mut value = String::new();
+            for p in arg.into_inner() {
+                match p.as_rule() {
+                    Rule::arg_name => name = Some(p.as_str().to_string()),
+                    Rule::arg_value => value = p.as_str().trim().to_string(),
+                    _ => {}
+                }
+            }
+            match name {
+                Some(name) => args.named.push((name, value)),
+                None => args.positional.push(value),
+            }
+        }
+        args
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.named
+            .iter()
+            .rev()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Writes the arguments back as typst, e.g. `fill: red, size: 2pt`.
+impl fmt::Display for Args {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let positional = self.positional.iter().cloned();
+        let named = self
+            .named
+            .iter()
+            .map(|(name, value)| format!("{name}: {value}"));
+        write!(
+            f,
+            "{}",
+            positional.chain(named).collect::<Vec<_>>().join(", ")
+        )
+    }
+}
+
+/// Removes the quotes of a typst string, e.g. `"1"` becomes `1`.
+pub fn unquote(value: &str) -> Option<&str> {
+    value.strip_prefix('"')?.strip_suffix('"')
+}
+
+/// Text of a typst string or content block, e.g. `[Eq.]` or `"Eq."` becomes `Eq.`.
+pub fn unwrap_text(value: &str) -> Option<&str> {
+    unquote(value).or_else(|| value.strip_prefix('[')?.strip_suffix(']'))
+}
+
+/// Items of a typst array, e.g. `("smcp", "onum")` gives `"smcp"` and `"onum"`.
+/// Nested arrays, dictionaries and strings are kept whole.
+pub fn array_items(value: &str) -> Vec<&str> {
+    let Some(inner) = value
+        .trim()
+        .strip_prefix('(')
+        .and_then(|v| v.strip_suffix(')'))
+    else {
+        return vec![value.trim()];
+    };
+    let mut items = Vec::new();
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut start = 0;
+    for (i, c) in inner.char_indices() {
+        match c {
+            '"' => in_string = !in_string,
+            _ if in_string => {}
+            '(' | '[' => depth += 1,
+            ')' | ']' => depth -= 1,
+            ',' if depth == 0 => {
+                items.push(inner[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    items.push(inner[start..].trim());
+    items.retain(|item| !item.is_empty());
+    items
+}
+
+/// Entries of a typst dictionary, e.g. `(name: "A")` gives `name` and `"A"`.
+pub fn dict_entries(value: &str) -> Vec<(&str, &str)> {
+    array_items(value)
+        .into_iter()
+        .filter_map(|item| item.split_once(':'))
+        .map(|(key, value)| (key.trim(), value.trim()))
+        .collect()
+}
+
+/// Converts a typst length to latex, expressing percentages relative to `relative_to`,
+/// e.g. `50%` with `\\textheight` gives `0.5\\textheight`. Fractions and sums are not converted.
+pub fn length_to_latex(length: &str, relative_to: &str) -> Option<String> {
+    const UNITS: [&str; 6] = ["pt", "mm", "cm", "in", "em", "ex"];
+    let length = length.trim();
+    if let Some(percent) = length.strip_suffix('%') {
+        let ratio = percent.trim().parse::<f64>().ok()? / 100.0;
+        return Some(if ratio == 1.0 {
+            relative_to.to_string()
+        } else {
+            format!("{ratio}{relative_to}")
+        });
+    }
+    let unit = UNITS.iter().find(|unit| length.ends_with(*unit))?;
+    length[..length.len() - unit.len()]
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .map(|_| length.to_string())
+}
+
+/// Latex counter style of a typst numbering pattern, e.g. `"i."` gives `roman`.
+pub fn numbering_style(pattern: &str) -> Option<&'static str> {
+    pattern.chars().find_map(|c| match c {
+        '1' => Some("arabic"),
+        'a' => Some("alph"),
+        'A' => Some("Alph"),
+        'i' => Some("roman"),
+        'I' => Some("Roman"),
+        _ => None,
+    })
+}
+
+/// Xcolor arguments of a typst color, e.g. `rgb("#eeeeee")` gives `[HTML]{EEEEEE}`, to be
+/// appended to a color command such as \\pagecolor. Named colors take the typst values.
+pub fn color_to_latex(color: &str) -> Option<String> {
+    const NAMED: [(&str, &str); 18] = [
+        ("black", "000000"),
+        ("gray", "AAAAAA"),
+        ("silver", "DDDDDD"),
+        ("white", "FFFFFF"),
+        ("navy", "001F3F"),
+        ("blue", "0074D9"),
+        ("aqua", "7FDBFF"),
+        ("teal", "39CCCC"),
+        ("eastern", "239DAD"),
+        ("purple", "B10DC9"),
+        ("fuchsia", "F012BE"),
+        ("maroon", "85144B"),
+        ("red", "FF4136"),
+        ("orange", "FF851B"),
+        ("yellow", "FFDC00"),
+        ("olive", "3D9970"),
+        ("green", "2ECC40"),
+        ("lime", "01FF70"),
+    ];
+    let color = color.trim();
+    if let Some((_, hex)) = NAMED.iter().find(|(name, _)| *name == color) {
+        return Some(format!("[HTML]{{{hex}}}"));
+    }
+    if let Some(args) = color.strip_prefix("luma") {
+        let level = array_items(args).first()?.parse::<f64>().ok()?;
+        return Some(format!("[gray]{{{:.3}}}", level / 255.0));
+    }
+    let args = array_items(color.strip_prefix("rgb")?);
+    if let [hex] = args.as_slice() {
+        let hex = unquote(hex)?.trim_start_matches('#');
+        let hex = match hex.len() {
+            3 => hex.chars().flat_map(|c| [c, c]).collect(),
+            6 => hex.to_string(),
+            _ => return None,
+        };
+        return hex
+            .chars()
+            .all(|c| c.is_ascii_hexdigit())
+            .then(|| format!("[HTML]{{{}}}", hex.to_uppercase()));
+    }
+    if args.len() != 3 {
+        return None;
+    }
+    if args.iter().all(|c| c.ends_with('%')) {
+        let ratios = args
+            .iter()
+            .map(|c| {
+                c.trim_end_matches('%')
+                    .parse::<f64>()
+                    .ok()
+                    .map(|p| p / 100.0)
+            })
+            .collect::<Option<Vec<_>>>()?;
+        let ratios: Vec<String> = ratios.iter().map(f64::to_string).collect();
+        Some(format!("[rgb]{{{}}}", ratios.join(",")))
+    } else {
+        let levels = args
+            .iter()
+            .map(|c| c.parse::<u8>().ok())
+            .collect::<Option<Vec<_>>>()?;
+        let levels: Vec<String> = levels.iter().map(u8::to_string).collect();
+        Some(format!("[RGB]{{{}}}", levels.join(",")))
+    }
+}
@@ -1,16 +1,29 @@
-use crate::document::Document;
+use crate::args::{
+    Args, array_items, color_to_latex, dict_entries, length_to_latex, numbering_style, unquote,
+    unwrap_text,
+};
+use crate::document::{Author, Document};
+use crate::functions::{Wrapper, context, convert_func, weight_series, word_spacing};
+use crate::math::cancel_color;
+use crate::settings::Settings;
 use crate::text::{Text, to_latex};
+use crate::warnings::warning;
 use anyhow::{Context, Result};
 use pest::{Parser, iterators::Pair};
 use pest_derive::Parser;
-use std::collections::HashSet;
+use std::collections::BTreeMap;
 
 #[derive(Parser)]
 #[grammar = "grammar.pest"]
 pub struct TypstParser;
 
-fn process_text(pair: Pair<Rule>) -> Vec<Text> {
-    fn process_inner(pair: Pair<Rule>, current: &mut String, result: &mut Vec<Text>) {
+fn process_text(pair: Pair<Rule>, settings: &Settings) -> Vec<Text> {
+    fn process_inner(
+        pair: Pair<Rule>,
+        settings: &Settings,
+        current: &mut String,
+        result: &mut Vec<Text>,
+    ) {
         match pair.as_rule() {
             Rule::newline => {
                 current.push('\n');
@@ -31,40 +44,170 @@ fn process_text(pair: Pair<Rule>) -> Vec<Text> {
                     pair.as_str().replace("typ", "tex")
                 )))
             }
-            Rule::raw_text | Rule::math | Rule::grid => {
+            Rule::raw_text | Rule::term_text | Rule::lone_delim | Rule::grid => {
                 current.push_str(pair.as_str());
             }
+            Rule::math => {
+                if !current.is_empty() {
+                    result.push(Text::Raw(std::mem::take(current)));
+                }
+                result.push(Text::Math(pair.as_str().into()));
+            }
             Rule::citation => {
+                // Adjacent citations, possibly on consecutive lines, are merged into one, but not
+                // across a paragraph break
+                if current.trim().is_empty()
+                    && current.matches('\n').count() <= 1
+                    && let Some(Text::Citation(keys)) = result.last_mut()
+                {
+                    keys.push_str(&std::mem::take(current));
+                    keys.push_str(pair.as_str());
+                    return;
+                }
                 if !current.is_empty() {
                     result.push(Text::Raw(std::mem::take(current)));
                 }
                 result.push(Text::Citation(pair.as_str().into()));
             }
+            // Code blocks nested in content are converted as at the top level
+            Rule::raw_block => match raw_block(&pair, settings) {
+                Some(block) => {
+                    if !current.is_empty() {
+                        result.push(Text::Raw(std::mem::take(current)));
+                    }
+                    result.push(Text::Latex(block));
+                }
+                None => current.push_str(pair.as_str()),
+            },
+            Rule::context => {
+                let expr = pair.into_inner().next().map_or("", |p| p.as_str());
+                // The expressions that cannot be converted are reported by unsupported
+                if let Some(latex) = context(expr) {
+                    if !current.is_empty() {
+                        result.push(Text::Raw(std::mem::take(current)));
+                    }
+                    result.push(Text::Latex(latex));
+                }
+            }
+            Rule::func => {
+                let mut name = "";
+                let mut args = Args::default();
+                let mut bodies = Vec::new();
+                for p in pair.into_inner() {
+                    match p.as_rule() {
+                        Rule::func_name => name = p.as_str(),
+                        Rule::func_args => args = Args::from_pairs(p.into_inner()),
+                        Rule::func_body => bodies.push(p),
+                        _ => {}
+                    }
+                }
+                let contents: Vec<&str> = bodies
+                    .iter()
+                    .map(|body| {
+                        let body = body.as_str();
+                        body.strip_prefix('[')
+                            .and_then(|b| b.strip_suffix(']'))
+                            .unwrap_or(body)
+                    })
+                    .collect();
+                let wrapper = convert_func(name, &args, &contents, settings).unwrap_or(Wrapper {
+                    remaining: args,
+                    ..Default::default()
+                });
+                let passthrough =
+                    !wrapper.remaining.positional.is_empty() || !wrapper.remaining.named.is_empty();
+
+                if !wrapper.open.is_empty() {
+                    if !current.is_empty() {
+                        result.push(Text::Raw(std::mem::take(current)));
+                    }
+                    result.push(Text::Latex(wrapper.open));
+                }
+                if passthrough {
+                    current.push_str(&format!("#{}({})", name, wrapper.remaining));
+                }
+                let bodies = if wrapper.drop_body {
+                    Vec::new()
+                } else {
+                    bodies
+                };
+                for body in bodies {
+                    if passthrough {
+                        current.push('[');
+                    }
+                    for inner_pair in body.into_inner() {
+                        process_inner(inner_pair, settings, current, result);
+                    }
+                    if passthrough {
+                        current.push(']');
+                    }
+                }
+                if !wrapper.close.is_empty() {
+                    if !current.is_empty() {
+                        result.push(Text::Raw(std::mem::take(current)));
+                    }
+                    result.push(Text::Latex(wrapper.close));
+                }
+            }
+            Rule::content_call => {
+                let mut inner = pair.into_inner();
+                let name = inner.next().map_or("", |p| p.as_str());
+                // Functions defined by #let become environments, others are left to pandoc
+                let env = settings.wrappers.contains(name).then(|| {
+                    settings
+                        .wrapper_envs
+                        .get(name)
+                        .map_or("quote", String::as_str)
+                });
+                match env {
+                    Some(env) => {
+                        if !current.is_empty() {
+                            result.push(Text::Raw(std::mem::take(current)));
+                        }
+                        result.push(Text::Latex(format!("\\begin{{{env}}}")));
+                    }
+                    None => current.push_str(&format!("#{name}[")),
+                }
+                for inner_pair in inner.flat_map(|body| body.into_inner()) {
+                    process_inner(inner_pair, settings, current, result);
+                }
+                match env {
+                    Some(env) => {
+                        if !current.is_empty() {
+                            result.push(Text::Raw(std::mem::take(current)));
+                        }
+                        result.push(Text::Latex(format!("\\end{{{env}}}")));
+                    }
+                    None => current.push(']'),
+                }
+            }
+            // The _line variants only differ by not spanning newlines, which the _text variants
+            // hold as newline pairs
             Rule::paren_text | Rule::paren_line => {
                 current.push('(');
                 for inner_pair in pair.into_inner() {
-                    process_inner(inner_pair, current, result);
+                    process_inner(inner_pair, settings, current, result);
                 }
                 current.push(')');
             }
             Rule::brack_text | Rule::brack_line => {
                 current.push('[');
                 for inner_pair in pair.into_inner() {
-                    process_inner(inner_pair, current, result);
+                    process_inner(inner_pair, settings, current, result);
                 }
                 current.push(']');
             }
             Rule::quote_text | Rule::quote_line => {
                 current.push('\"');
                 for inner_pair in pair.into_inner() {
-                    process_inner(inner_pair, current, result);
+                    process_inner(inner_pair, settings, current, result);
                 }
                 current.push('\"');
             }
             _ => {
                 // For other rules, recursively process their inner pairs
                 for inner_pair in pair.into_inner() {
-                    process_inner(inner_pair, current, result);
+                    process_inner(inner_pair, settings, current, result);
                 }
             }
         }
@@ -72,68 +215,1118 @@ fn process_text(pair: Pair<Rule>) -> Vec<Text> {
 
     let mut result = Vec::new();
     let mut current = String::new();
-    process_inner(pair, &mut current, &mut result);
+    process_inner(pair, settings, &mut current, &mut result);
     if !current.is_empty() {
         result.push(Text::Raw(current))
     }
     result
 }
 
-pub fn parse(content: &str, citations: HashSet<String>) -> Result<Document> {
-    let pairs = TypstParser::parse(Rule::program, &content)
+/// Whether a line of latex closes a block environment or a display equation.
+fn is_block_end(line: &str) -> bool {
+    let line = line.trim();
+    line.starts_with("\\end{") || line.ends_with("\\]")
+}
+
+/// Appends a block, separated by a blank line from a block directly preceding it.
+fn push_block(content: &mut String, block: &str) {
+    let previous = content.trim_end_matches('\n');
+    if previous.lines().last().is_some_and(is_block_end) {
+        content.truncate(previous.len());
+        content.push_str("\n\n");
+    }
+    content.push_str(block);
+}
+
+/// Latex float specifier of a typst figure placement, None for the default placement.
+fn float_placement(placement: &str) -> Option<String> {
+    match placement {
+        "top" => Some("t".into()),
+        "bottom" => Some("b".into()),
+        "auto" => None,
+        _ => {
+            warning!(
+                "unsupported figure placement {:?}, using the default",
+                placement
+            );
+            None
+        }
+    }
+}
+
+/// Whether a typst table stroke draws rules, None if the stroke is not supported.
+fn table_rules(stroke: &str) -> Option<bool> {
+    if stroke == "none" {
+        return Some(false);
+    }
+    if stroke.starts_with('(') {
+        warning!("unsupported table stroke {}, using the default", stroke);
+        return None;
+    }
+    // The stroke may also hold a color, e.g. 1pt + red
+    let thickness = stroke
+        .split('+')
+        .find_map(|part| length_to_latex(part, ""))
+        .and_then(|length| {
+            length
+                .trim_end_matches(char::is_alphabetic)
+                .parse::<f64>()
+                .ok()
+        });
+    Some(thickness != Some(0.0))
+}
+
+/// Column specifier of a typst cell alignment, e.g. `center` gives `c`.
+fn column_align(align: &str) -> &'static str {
+    match align {
+        "center" => "c",
+        "right" => "r",
+        _ => "l",
+    }
+}
+
+/// Arguments and content of a `table.cell` call, e.g. `(colspan: 2)[c]` gives `colspan: 2` and `[c]`.
+/// The content is either the trailing content block or a positional argument.
+fn table_cell(call: &str) -> (Vec<(&str, &str)>, &str) {
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut end = call.len();
+    for (i, c) in call.char_indices() {
+        match c {
+            '"' => in_string = !in_string,
+            _ if in_string => {}
+            '(' | '[' => depth += 1,
+            ')' | ']' if depth == 1 => {
+                end = i + 1;
+                break;
+            }
+            ')' | ']' => depth -= 1,
+            _ => {}
+        }
+    }
+    let (args, mut body) = call.split_at(end);
+    let mut named = Vec::new();
+    for item in array_items(args) {
+        match item.split_once(':') {
+            Some((name, value)) if !item.starts_with(['[', '"']) => {
+                named.push((name.trim(), value.trim()))
+            }
+            _ => body = item,
+        }
+    }
+    (named, body.trim())
+}
+
+/// Cells given as expressions, e.g. the cells of `table.header(...)` or `table.cell(...)[...]`.
+fn expression_cells(value: &str) -> Vec<&str> {
+    match value
+        .strip_prefix("table.header")
+        .or_else(|| value.strip_prefix("table.footer"))
+    {
+        Some(cells) => array_items(cells)
+            .into_iter()
+            .flat_map(expression_cells)
+            .collect(),
+        None => vec![value],
+    }
+}
+
+/// Converts the content of a cell given as an expression, with the number of columns it spans.
+fn expression_cell(value: &str, settings: &Settings) -> Result<(String, usize)> {
+    let (colspan, content) = match value.strip_prefix("table.cell") {
+        Some(call) => {
+            let (named, body) = table_cell(call);
+            let colspan = named
+                .iter()
+                .find(|(name, _)| *name == "colspan")
+                .and_then(|(_, value)| value.parse().ok())
+                .unwrap_or(1);
+            (colspan, body)
+        }
+        None => (1, value),
+    };
+    // Content blocks are converted as text, other expressions e.g. $x$ or "a" are left to pandoc
+    let text = content
+        .strip_prefix('[')
+        .and_then(|c| c.strip_suffix(']'))
+        .and_then(|c| {
+            let text = TypstParser::parse(Rule::text, c).ok()?.next()?;
+            (text.as_str() == c).then_some(text)
+        });
+    let latex = match text {
+        Some(text) => to_latex(process_text(text, settings), settings)?,
+        None if content == "[]" => String::new(),
+        None => to_latex(vec![Text::Raw(content.into())], settings)?,
+    };
+    Ok((latex, colspan.max(1)))
+}
+
+/// Converts the cells and arguments of a typst table to a tabular, with rules unless the stroke is none.
+fn table(pair: Pair<Rule>, settings: &Settings) -> Result<String> {
+    let args = Args::from_pairs(pair.clone().into_inner());
+    let mut columns = 1;
+    let mut aligns = vec!["l"];
+    let mut rules = !settings.table_borderless;
+    for (name, value) in &args.named {
+        match name.as_str() {
+            "columns" => columns = value.parse().unwrap_or(array_items(value).len()),
+            "align" => aligns = array_items(value).into_iter().map(column_align).collect(),
+            "stroke" => rules = table_rules(value).unwrap_or(rules),
+            // The other arguments are reported by unsupported
+            _ => {}
+        }
+    }
+
+    let columns = columns.max(1);
+    // Cells with the number of columns they span, and the number of columns filled so far
+    let mut cells: Vec<(String, usize)> = Vec::new();
+    let mut filled: usize = 0;
+    // Explicit rules, as row boundaries with the covered columns and column boundaries
+    let mut hlines: Vec<(usize, Option<(usize, usize)>)> = Vec::new();
+    let mut vlines: Vec<usize> = Vec::new();
+    for p in pair.into_inner() {
+        match p.as_rule() {
+            Rule::table_cell => {
+                let text = p
+                    .into_inner()
+                    .next()
+                    .map(|text| process_text(text, settings))
+                    .unwrap_or_default();
+                cells.push((to_latex(text, settings)?, 1));
+                filled += 1;
+            }
+            Rule::arg
+                if p.clone()
+                    .into_inner()
+                    .all(|q| q.as_rule() != Rule::arg_name) =>
+            {
+                let value = p.as_str().trim();
+                let index = |entries: &[(&str, &str)], key: &str| {
+                    entries
+                        .iter()
+                        .find(|(k, _)| *k == key)
+                        .and_then(|(_, v)| v.parse::<usize>().ok())
+                };
+                if let Some(line_args) = value.strip_prefix("table.hline") {
+                    let entries = dict_entries(line_args);
+                    let y = index(&entries, "y").unwrap_or(filled.div_ceil(columns));
+                    if index(&entries, "y").is_none() && !filled.is_multiple_of(columns) {
+                        warning!("table.hline() in the middle of a row, placed after the row");
+                    }
+                    let span = match (index(&entries, "start"), index(&entries, "end")) {
+                        (None, None) => None,
+                        (start, end) => Some((start.unwrap_or(0), end.unwrap_or(columns))),
+                    };
+                    hlines.push((y, span));
+                } else if let Some(line_args) = value.strip_prefix("table.vline") {
+                    let entries = dict_entries(line_args);
+                    if entries.iter().any(|(k, _)| !matches!(*k, "x")) {
+                        warning!(
+                            "only the position of table.vline() is supported, drawn across the table"
+                        );
+                    }
+                    vlines.push(index(&entries, "x").unwrap_or(filled % columns));
+                } else {
+                    for cell in expression_cells(value) {
+                        let (latex, colspan) = expression_cell(cell, settings)?;
+                        cells.push((latex, colspan));
+                        filled += colspan;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Cells with their first column and span, those spanning the end of a row being cut there
+    let mut rows: Vec<Vec<(String, usize, usize)>> = Vec::new();
+    let mut width = columns;
+    for (latex, colspan) in cells {
+        if width == columns {
+            rows.push(Vec::new());
+            width = 0;
+        }
+        let colspan = colspan.min(columns - width);
+        if let Some(row) = rows.last_mut() {
+            row.push((latex, width, colspan));
+        }
+        width += colspan;
+    }
+    if rules {
+        // The explicit rules are part of the full grid
+        hlines = (0..=rows.len()).map(|y| (y, None)).collect();
+        vlines = (0..=columns).collect();
+    }
+    for &(y, _) in hlines.iter().filter(|(y, _)| *y > rows.len()) {
+        warning!("table.hline() at row {y} is outside the table, ignored");
+    }
+    let align = |x: usize| aligns.get(x % aligns.len().max(1)).copied().unwrap_or("l");
+    let mut spec = String::new();
+    for x in 0..=columns {
+        if vlines.contains(&x) {
+            spec.push('|');
+        }
+        if x < columns {
+            spec.push_str(align(x));
+        }
+    }
+
+    let mut latex = format!("\\begin{{tabular}}{{{spec}}}\n");
+    for y in 0..=rows.len() {
+        for (_, span) in hlines.iter().filter(|(row, _)| *row == y) {
+            latex += &match span {
+                None => "\\hline\n".to_string(),
+                Some((start, end)) => format!("\\cline{{{}-{}}}\n", start + 1, end),
+            };
+        }
+        if let Some(row) = rows.get(y) {
+            let row: Vec<String> = row
+                .iter()
+                .map(|(cell, x, colspan)| match colspan {
+                    1 => cell.clone(),
+                    // The span keeps the alignment of its first column and the rules around it
+                    _ => format!(
+                        "\\multicolumn{{{colspan}}}{{{}{}{}}}{{{cell}}}",
+                        if *x == 0 && vlines.contains(&0) {
+                            "|"
+                        } else {
+                            ""
+                        },
+                        align(*x),
+                        if vlines.contains(&(x + colspan)) {
+                            "|"
+                        } else {
+                            ""
+                        },
+                    ),
+                })
+                .collect();
+            latex += &format!("{} \\\\\n", row.join(" & "));
+        }
+    }
+    latex += "\\end{tabular}";
+    Ok(latex)
+}
+
+/// Converts a code block, copying its body byte for byte. None if left to pandoc for its highlighting.
+fn raw_block(pair: &Pair<Rule>, settings: &Settings) -> Option<String> {
+    let mut lang = "";
+    let mut body = "";
+    for p in pair.clone().into_inner() {
+        match p.as_rule() {
+            Rule::raw_lang => lang = p.as_str(),
+            Rule::raw_body => body = p.as_str(),
+            _ => {}
+        }
+    }
+    if settings.minted {
+        let lang = if lang.is_empty() { "text" } else { lang };
+        Some(format!(
+            "\\begin{{minted}}{{{lang}}}\n{body}\\end{{minted}}"
+        ))
+    } else if settings.preserve_raw_blocks {
+        Some(format!("\\begin{{verbatim}}\n{body}\\end{{verbatim}}"))
+    } else {
+        None
+    }
+}
+
+/// Removes the page breaks from the content of a float, where latex does not allow them. The
+/// spaces around a removed break are collapsed, e.g. `Body \\clearpage more` gives `Body more`.
+fn strip_pagebreaks(mut latex: String) -> String {
+    for command in ["\\cleardoublepage", "\\clearpage"] {
+        if latex.contains(command) {
+            warning!("page break inside a figure, removed");
+        }
+        while let Some(start) = latex.find(command) {
+            let before = latex[..start].trim_end_matches([' ', '\t']);
+            let after = latex[start + command.len()..].trim_start_matches([' ', '\t']);
+            let separator = if before.is_empty()
+                || after.is_empty()
+                || before.ends_with('\n')
+                || after.starts_with('\n')
+            {
+                ""
+            } else {
+                " "
+            };
+            latex = format!("{before}{separator}{after}");
+        }
+    }
+    latex
+}
+
+/// Pygments style of a typst raw theme, e.g. `"monokai.tmTheme"` gives `monokai`.
+fn minted_style(theme: &str) -> Option<String> {
+    const STYLES: [&str; 14] = [
+        "default",
+        "monokai",
+        "dracula",
+        "solarized-dark",
+        "solarized-light",
+        "github-dark",
+        "one-dark",
+        "nord",
+        "gruvbox-dark",
+        "gruvbox-light",
+        "zenburn",
+        "vs",
+        "xcode",
+        "material",
+    ];
+    let path = unquote(theme)?;
+    let stem = path
+        .rsplit('/')
+        .next()?
+        .trim_end_matches(".tmTheme")
+        .to_lowercase();
+    STYLES.contains(&stem.as_str()).then_some(stem)
+}
+
+/// Constructs handled by parse and process_text, besides the set rules and functions.
+pub const CONSTRUCTS: [&str; 20] = [
+    "headings (=, ==, ===)",
+    "theorems (#theorem, #lemma, #corollary, #proposition, #definition, #example, #property)",
+    "#proof",
+    "#figure",
+    "#table",
+    "#columns",
+    "#grid (cells left to pandoc)",
+    "code blocks",
+    "term lists (/ term: description)",
+    "#include",
+    "math",
+    "citations and references (@key)",
+    "#context (counter displays and lookups)",
+    "#set align(...)",
+    "#show heading (presets)",
+    "#show raw: set text(font: ...)",
+    "#let (calls to single-argument content functions)",
+    "#show: ams-article.with(...)",
+    "BEGIN TEX / END TEX comments",
+    "BEGIN NO TEX / END NO TEX comments",
+];
+
+/// Arguments of set rules handled by set_rule, as (target, argument) pairs.
+pub const SET_RULES: [(&str, &str); 19] = [
+    ("document", "title"),
+    ("document", "author"),
+    ("page", "numbering"),
+    ("page", "fill"),
+    ("raw", "theme"),
+    ("figure", "placement"),
+    ("math.equation", "supplement"),
+    ("math.vec", "delim"),
+    ("math.cancel", "stroke"),
+    ("heading", "supplement"),
+    ("heading", "numbering"),
+    ("table", "stroke"),
+    ("par.line", "numbering"),
+    ("par", "first-line-indent"),
+    ("text", "weight"),
+    ("text", "spacing"),
+    ("text", "number-type"),
+    ("text", "dir"),
+    ("text", "lang"),
+];
+
+/// Presets of `#show heading` rules.
+enum HeadingShow {
+    /// Whether headings are numbered, e.g. unnumbered with `it => it.body`.
+    Numbered(bool),
+    /// Headings in small capitals, e.g. `#show heading: smallcaps`.
+    SmallCaps,
+}
+
+/// Preset of a `#show heading` rule, None if the rule is not a known preset.
+fn heading_show(body: &str) -> Option<HeadingShow> {
+    let body: String = body.chars().filter(|c| !c.is_whitespace()).collect();
+    if let Some(numbering) = body.strip_prefix("setheading(numbering:") {
+        return Some(HeadingShow::Numbered(!numbering.starts_with("none")));
+    }
+    if body == "smallcaps" || body == "it=>smallcaps(it)" {
+        return Some(HeadingShow::SmallCaps);
+    }
+    let shows_body = body.starts_with("it=>") && body.contains("it.body");
+    if body.contains("counter(heading).display") {
+        // The number is added by hand in front of the body
+        Some(HeadingShow::Numbered(true))
+    } else if shows_body && !body.contains("it.numbering") {
+        Some(HeadingShow::Numbered(false))
+    } else {
+        None
+    }
+}
+
+/// Redefinitions of the section numbers for a heading numbering pattern, e.g. `"1)"` gives
+/// `\\renewcommand{\\thesection}{\\arabic{section})}`. Deeper levels repeat the last counting
+/// symbol and separator, the trailing dot of the usual patterns is left out as in latex.
+fn heading_numbering(pattern: &str) -> Option<Vec<String>> {
+    let mut symbols = Vec::new();
+    let mut separators = vec![String::new()];
+    for c in pattern.chars() {
+        match numbering_style(&c.to_string()) {
+            Some(style) => {
+                symbols.push(style);
+                separators.push(String::new());
+            }
+            None => separators.last_mut()?.push(c),
+        }
+    }
+    if symbols.is_empty() {
+        return None;
+    }
+    let last = separators.pop().unwrap_or_default();
+    let prefix = separators.remove(0);
+    // Without a separator, the levels are joined by the suffix, e.g. "1)" gives 1)1)
+    let fallback = if last.is_empty() { "." } else { last.as_str() };
+    let separator = |index: usize| {
+        separators
+            .get(index - 1)
+            .or(separators.last())
+            .map_or(fallback, |s| s.as_str())
+    };
+    let suffix = if last == "." { "" } else { last.as_str() };
+    let counters = ["section", "subsection", "subsubsection"];
+
+    let mut commands = Vec::new();
+    for (level, counter) in counters.iter().enumerate() {
+        let mut number = prefix.clone();
+        for (index, counter) in counters[..=level].iter().enumerate() {
+            if index > 0 {
+                number += separator(index);
+            }
+            let style = symbols.get(index).or(symbols.last())?;
+            number += &format!("\\{style}{{{counter}}}");
+        }
+        number += suffix;
+        let default = format!("\\arabic{{{}}}", counters[..=level].join("}.\\arabic{"));
+        if number != default {
+            commands.push(format!("\\renewcommand{{\\the{counter}}}{{{number}}}"));
+        }
+    }
+    Some(commands)
+}
+
+/// Font of a `#show raw` rule setting the code font, e.g. `set text(font: "Fira Code")`.
+fn raw_font(body: &str) -> Option<&str> {
+    let args = body
+        .trim()
+        .strip_prefix("set")?
+        .trim_start()
+        .strip_prefix("text")?
+        .trim_start();
+    dict_entries(args)
+        .into_iter()
+        .find(|(name, _)| *name == "font")
+        .map(|(_, font)| font)
+}
+
+/// Package setting the monospaced font of latex, for the first known font of a typst font list.
+fn mono_package(fonts: &str) -> Option<&'static str> {
+    array_items(fonts).into_iter().find_map(|font| {
+        Some(match unquote(font)?.to_lowercase().as_str() {
+            "fira code" | "fira mono" => "\\usepackage[scale=0.85]{FiraMono}",
+            "dejavu sans mono" => "\\usepackage[scaled=0.85]{DejaVuSansMono}",
+            "inconsolata" => "\\usepackage{inconsolata}",
+            "source code pro" => "\\usepackage{sourcecodepro}",
+            "courier" | "courier new" => "\\usepackage{courier}",
+            "ibm plex mono" => "\\usepackage[scale=0.85]{plex-mono}",
+            // The default monospaced font of latex
+            "latin modern mono" | "new computer modern mono" => "",
+            _ => return None,
+        })
+    })
+}
+
+/// Describes the constructs of a pair and of its inner pairs that the conversion drops, e.g. `#show`
+/// or `#set text(size)`.
+fn unsupported(pair: &Pair<Rule>) -> Vec<String> {
+    let mut result = match pair.as_rule() {
+        Rule::command => {
+            let name: String = pair.as_str()[1..]
+                .chars()
+                .take_while(char::is_ascii_alphabetic)
+                .collect();
+            // Imports only bring the template into scope, the template itself is converted
+            match name.as_str() {
+                "import" => Vec::new(),
+                _ => vec![format!("#{name}")],
+            }
+        }
+        // Only functions of a single argument are converted, see parse
+        Rule::let_def => {
+            let params = match pair.clone().into_inner().nth(1) {
+                Some(p) if p.as_rule() == Rule::let_params => Args::from_pairs(p.into_inner()),
+                _ => Args::default(),
+            };
+            match params.positional.len() {
+                1 => Vec::new(),
+                _ => vec!["#let".into()],
+            }
+        }
+        Rule::show_heading => match heading_show(pair.clone().into_inner().as_str()) {
+            Some(_) => Vec::new(),
+            None => vec!["#show heading".into()],
+        },
+        Rule::show_raw => match raw_font(pair.clone().into_inner().as_str()) {
+            Some(_) => Vec::new(),
+            None => vec!["#show raw".into()],
+        },
+        // The content is given by the image, table or content block, other arguments are dropped
+        Rule::figure => {
+            let args = Args::from_pairs(pair.clone().into_inner());
+            let mut result: Vec<String> = args
+                .named
+                .iter()
+                .filter(|(name, _)| !matches!(name.as_str(), "placement" | "numbering"))
+                .map(|(name, _)| format!("#figure({name})"))
+                .collect();
+            if !args.positional.is_empty() {
+                result.push("#figure(...)".into());
+            }
+            result
+        }
+        Rule::set_rule => {
+            let mut inner = pair.clone().into_inner();
+            let target = inner.next().map(|p| p.as_str()).unwrap_or_default();
+            let args = Args::from_pairs(inner);
+            let mut result: Vec<String> = args
+                .named
+                .iter()
+                .filter(|(name, _)| !SET_RULES.contains(&(target, name.as_str())))
+                .map(|(name, _)| format!("#set {target}({name})"))
+                .collect();
+            if !args.positional.is_empty() && target != "align" {
+                result.push(format!("#set {target}(...)"));
+            }
+            result
+        }
+        Rule::table | Rule::fig_table => {
+            let args = Args::from_pairs(pair.clone().into_inner());
+            let mut result: Vec<String> = args
+                .named
+                .iter()
+                .filter(|(name, _)| !matches!(name.as_str(), "columns" | "align" | "stroke"))
+                .map(|(name, _)| format!("#table({name})"))
+                .collect();
+            // Cells only span columns, e.g. rowspan is dropped
+            for cell in args
+                .positional
+                .iter()
+                .flat_map(|value| expression_cells(value))
+            {
+                if let Some(call) = cell.strip_prefix("table.cell") {
+                    result.extend(
+                        table_cell(call)
+                            .0
+                            .iter()
+                            .filter(|(name, _)| *name != "colspan")
+                            .map(|(name, _)| format!("table.cell({name})")),
+                    );
+                }
+            }
+            result
+        }
+        Rule::context => {
+            let expr = pair.clone().into_inner().next().map_or("", |p| p.as_str());
+            match context(expr) {
+                Some(_) => Vec::new(),
+                None => vec!["#context".into()],
+            }
+        }
+        _ => Vec::new(),
+    };
+    for inner in pair.clone().into_inner() {
+        result.extend(unsupported(&inner));
+    }
+    result
+}
+
+/// Prints the parse tree to stderr, one rule per line with its span, and the text of the leaves.
+pub fn trace_rules(content: &str) -> Result<()> {
+    fn trace(pair: Pair<Rule>, depth: usize) {
+        let span = pair.as_span();
+        let (line, column) = span.start_pos().line_col();
+        let text = match pair.clone().into_inner().next() {
+            Some(_) => String::new(),
+            None => format!(" {:?}", pair.as_str()),
+        };
+        eprintln!(
+            "{}{:?} {}:{} {}..{}{}",
+            "  ".repeat(depth),
+            pair.as_rule(),
+            line,
+            column,
+            span.start(),
+            span.end(),
+            text
+        );
+        for inner in pair.into_inner() {
+            trace(inner, depth + 1);
+        }
+    }
+
+    let pairs = TypstParser::parse(Rule::program, content)
+        .with_context(|| "Failed to parse input according to grammar")?;
+    for pair in pairs {
+        trace(pair, 0);
+    }
+    Ok(())
+}
+
+/// Counts the constructs that the conversion would drop, including nested ones, without converting
+/// the document.
+pub fn unsupported_rules(content: &str) -> Result<BTreeMap<String, usize>> {
+    let pairs = TypstParser::parse(Rule::program, content)
+        .with_context(|| "Failed to parse input according to grammar")?;
+
+    let mut counts = BTreeMap::new();
+    for pair in pairs {
+        for name in unsupported(&pair) {
+            *counts.entry(name).or_insert(0) += 1;
+        }
+    }
+    Ok(counts)
+}
+
+/// Names the references to a latex counter, for both cleveref and \\autoref.
+fn supplement(document: &mut Document, counter: &str, value: &str) {
+    if let Some(name) = unwrap_text(value) {
+        document
+            .preamble
+            .push(format!("\\crefname{{{counter}}}{{{name}}}{{{name}}}"));
+        document.preamble.push(format!(
+            "\\renewcommand{{\\{counter}autorefname}}{{{name}}}"
+        ));
+    }
+}
+
+/// Paragraph alignment declaration of a typst alignment, e.g. `center + horizon` gives `\\centering`.
+fn align_declaration(align: &str) -> Option<&'static str> {
+    align.split('+').find_map(|part| match part.trim() {
+        "center" => Some("\\centering"),
+        "right" | "end" => Some("\\raggedleft"),
+        "left" => Some("\\raggedright"),
+        // Typst justifies start-aligned paragraphs only if asked to, latex by default
+        "start" => Some("\\justifying"),
+        _ => None,
+    })
+}
+
+/// Escapes the latex special characters of an email address.
+fn escape_email(email: &str) -> String {
+    email
+        .chars()
+        .map(|c| match c {
+            '_' | '%' | '&' | '#' => format!("\\{c}"),
+            c => c.to_string(),
+        })
+        .collect()
+}
+
+/// Sets the authors of `#set document(author: ...)`, either names or dictionaries such as
+/// `(name: "A", affiliation: "X", email: "a@x")`.
+fn document_authors(document: &mut Document, value: &str, settings: &Settings) -> Result<()> {
+    let text = |value: &str| {
+        to_latex(
+            vec![Text::Raw(unwrap_text(value).unwrap_or(value).into())],
+            settings,
+        )
+    };
+    // A single author may be given as a dictionary instead of an array of them
+    let items = if dict_entries(value).iter().any(|(key, _)| *key == "name") {
+        vec![value]
+    } else {
+        array_items(value)
+    };
+
+    let mut authors = Vec::new();
+    for item in items {
+        let mut author = Author::default();
+        if item.starts_with('(') {
+            for (key, value) in dict_entries(item) {
+                match key {
+                    "name" => author.name = text(value)?,
+                    "affiliation" => author.affiliation = Some(text(value)?),
+                    "email" => author.email = Some(escape_email(unquote(value).unwrap_or(value))),
+                    _ => warning!("unsupported author field {:?}, ignored", key),
+                }
+            }
+        } else {
+            author.name = text(item)?;
+        }
+        authors.push(author);
+    }
+
+    if authors
+        .iter()
+        .all(|a| a.affiliation.is_none() && a.email.is_none())
+    {
+        let names: Vec<String> = authors.into_iter().map(|a| a.name).collect();
+        document.authors = Some(names.join(" \\and "));
+    } else {
+        document.structured_authors = authors;
+    }
+    Ok(())
+}
+
+/// Babel language of a typst language code, e.g. `fr` gives `french`.
+fn babel_language(lang: &str) -> Option<&'static str> {
+    Some(match lang {
+        "en" => "english",
+        "fr" => "french",
+        "de" => "ngerman",
+        "es" => "spanish",
+        "it" => "italian",
+        "pt" => "portuguese",
+        "nl" => "dutch",
+        "sv" => "swedish",
+        "da" => "danish",
+        "nb" => "norsk",
+        "fi" => "finnish",
+        "pl" => "polish",
+        "cs" => "czech",
+        "ru" => "russian",
+        "el" => "greek",
+        "tr" => "turkish",
+        _ => return None,
+    })
+}
+
+/// Applies a set rule, returning the latex to insert at its position.
+fn set_rule(pair: Pair<Rule>, document: &mut Document, settings: &mut Settings) -> Result<String> {
+    let mut inner = pair.into_inner();
+    let target = inner.next().map(|p| p.as_str()).unwrap_or_default();
+    let args = Args::from_pairs(inner);
+    let mut latex = String::new();
+
+    if target == "align"
+        && let Some(value) = args.positional.first()
+    {
+        match align_declaration(value) {
+            // Justified is the default, declared only to undo a previous alignment
+            Some(align) if settings.alignment.unwrap_or("\\justifying") != align => {
+                settings.alignment = Some(align);
+                latex += &format!("{align}\n");
+            }
+            Some(_) => {}
+            None => warning!("unsupported alignment {}, ignored", value),
+        }
+    }
+
+    for (name, value) in &args.named {
+        match (target, name.as_str()) {
+            ("page", "numbering") => {
+                if value == "none" {
+                    settings.page_numbering_hidden = true;
+                    latex += "\\pagestyle{empty}\n";
+                } else if let Some(style) = unquote(value).and_then(numbering_style) {
+                    if settings.page_numbering_hidden {
+                        settings.page_numbering_hidden = false;
+                        latex += "\\pagestyle{plain}\n";
+                    }
+                    latex += &format!("\\pagenumbering{{{style}}}\n");
+                }
+            }
+            // The default white page needs nothing
+            ("page", "fill") if value == "none" || value == "white" => {}
+            ("page", "fill") => match color_to_latex(value) {
+                Some(color) => document.preamble.push(format!("\\pagecolor{color}")),
+                None => warning!("unsupported page fill {}, ignored", value),
+            },
+            ("par.line", "numbering") => {
+                if value == "none" {
+                    latex += "\\nolinenumbers\n";
+                } else if let Some(style) = unquote(value).and_then(numbering_style) {
+                    if style != "arabic" {
+                        document.preamble.push(format!(
+                            "\\renewcommand{{\\thelinenumber}}{{\\{style}{{linenumber}}}}"
+                        ));
+                    }
+                    latex += "\\linenumbers\n";
+                }
+            }
+            // Either a length or a dictionary, e.g. (amount: 1em, all: true) also indenting the first
+            // paragraph after a heading
+            ("par", "first-line-indent") => {
+                let (amount, all) = if value.starts_with('(') {
+                    let entries = dict_entries(value);
+                    let entry = |key| entries.iter().find(|(k, _)| *k == key).map(|(_, v)| *v);
+                    (entry("amount"), entry("all") == Some("true"))
+                } else {
+                    (Some(value.as_str()), false)
+                };
+                if let Some(amount) = amount {
+                    match length_to_latex(amount, "\\linewidth") {
+                        Some(length) => {
+                            latex += &format!("\\setlength{{\\parindent}}{{{length}}}\n")
+                        }
+                        None => warning!("unsupported first line indent {}, ignored", amount),
+                    }
+                }
+                let package = "\\usepackage{indentfirst}";
+                if all && !document.preamble.iter().any(|line| line == package) {
+                    document.preamble.push(package.into());
+                }
+            }
+            ("text", "weight") => match weight_series(value) {
+                // Medium is the default series, declared only to undo a previous weight
+                Some(series) if settings.font_series.unwrap_or("\\mdseries") != series => {
+                    settings.font_series = Some(series);
+                    latex += &format!("{series}\n");
+                }
+                Some(_) => {}
+                None => warning!("unsupported font weight {}, ignored", value),
+            },
+            // The font spacing is the default, restored only to undo a previous spacing
+            ("text", "spacing") if value == "100%" => {
+                latex += settings
+                    .word_spacing
+                    .take()
+                    .map_or("", |_| "\\spaceskip=0pt\\relax\n");
+            }
+            ("text", "spacing") => match word_spacing(value) {
+                Some(spacing) => {
+                    latex += &format!("{spacing}\n");
+                    settings.word_spacing = Some(spacing);
+                }
+                None => warning!("unsupported word spacing {}, ignored", value),
+            },
+            // Document-wide old-style figures depend on the font, those of cfr-lm are close to the
+            // default Latin Modern. Lining figures are the default.
+            ("text", "number-type") => match unquote(value) {
+                Some("old-style") => {
+                    let package = "\\usepackage[rm={oldstyle=true},sf={oldstyle=true}]{cfr-lm}";
+                    if !document.preamble.iter().any(|line| line == package) {
+                        document.preamble.push(package.into());
+                    }
+                }
+                Some("lining") => {}
+                _ => warning!("unsupported number type {}, ignored", value),
+            },
+            ("text", "dir") => match value.as_str() {
+                "rtl" => latex += "\\setRTL\n",
+                "ltr" => latex += "\\setLTR\n",
+                _ => warning!("unsupported text direction {}, ignored", value),
+            },
+            ("text", "lang") => {
+                let lang = unquote(value).unwrap_or(value);
+                let babel = document
+                    .preamble
+                    .iter_mut()
+                    .find(|line| line.ends_with("]{babel}"));
+                match (babel_language(lang), babel) {
+                    (Some(language), None) => document
+                        .preamble
+                        .push(format!("\\usepackage[main={language}]{{babel}}")),
+                    // Later languages are loaded too, the first one staying the main language
+                    (Some(language), Some(babel)) => {
+                        if !babel.contains(language) {
+                            *babel = babel.replacen('[', &format!("[{language},"), 1);
+                        }
+                        latex += &format!("\\selectlanguage{{{language}}}\n");
+                    }
+                    (None, _) => warning!("unknown language {:?}, babel is not loaded", lang),
+                }
+                settings.lang = Some(lang.into());
+            }
+            ("raw", "theme") => match minted_style(value) {
+                Some(style) if settings.minted => document
+                    .preamble
+                    .push(format!("\\usemintedstyle{{{style}}}")),
+                Some(_) => warning!("raw themes are only supported with --minted"),
+                None => warning!("unknown raw theme {}, using the default", value),
+            },
+            ("figure", "placement") => settings.figure_placement = float_placement(value),
+            ("table", "stroke") => {
+                if let Some(rules) = table_rules(value) {
+                    settings.table_borderless = !rules;
+                }
+            }
+            ("math.vec", "delim") => settings.vec_delim = Some(value.clone()),
+            ("math.cancel", "stroke") => {
+                if let Some(color) = cancel_color(value) {
+                    latex += &format!("\\renewcommand{{\\CancelColor}}{{{color}}}\n");
+                }
+            }
+            ("math.equation", "supplement") => supplement(document, "equation", value),
+            ("heading", "supplement") => supplement(document, "section", value),
+            ("heading", "numbering") if value == "none" => settings.unnumbered_headings = true,
+            ("heading", "numbering") => match unquote(value).and_then(heading_numbering) {
+                Some(commands) => {
+                    settings.unnumbered_headings = false;
+                    document.preamble.extend(commands);
+                }
+                None => warning!("unsupported heading numbering {}, ignored", value),
+            },
+            ("document", "author") => document_authors(document, value, settings)?,
+            ("document", "title") => {
+                document.title = match unwrap_text(value) {
+                    Some(title) => Some(to_latex(vec![Text::Raw(title.into())], settings)?),
+                    None => None,
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(latex)
+}
+
+pub fn parse(content: &str, settings: &Settings) -> Result<Document> {
+    let pairs = TypstParser::parse(Rule::program, content)
         .with_context(|| "Failed to parse input according to grammar")?;
 
     let mut content = String::new();
     let mut document = Document::default();
+    let mut settings = settings.clone();
 
-    fn get_str(pair: Pair<Rule>, citations: &HashSet<String>) -> Result<String> {
-        to_latex(process_text(pair), citations)
+    fn get_str(pair: Pair<Rule>, settings: &Settings) -> Result<String> {
+        to_latex(process_text(pair, settings), settings)
     }
 
-    fn get_inner_str(pair: Pair<Rule>, citations: &HashSet<String>) -> Result<String> {
+    fn get_inner_str(pair: Pair<Rule>, settings: &Settings) -> Result<String> {
         let vec = pair
             .into_inner()
             .next()
-            .map(process_text)
+            .map(|text| process_text(text, settings))
             .unwrap_or_default();
-        to_latex(vec, citations)
+        to_latex(vec, settings)
     }
 
     macro_rules! gs {
         ($pair:expr) => {
-            get_str($pair, &citations)?
+            get_str($pair, &settings)?
         };
     }
 
     macro_rules! gis {
         ($pair:expr) => {
-            get_inner_str($pair, &citations)?
+            get_inner_str($pair, &settings)?
         };
     }
 
-    for pair in pairs {
+    let mut pairs = pairs;
+    while let Some(pair) = pairs.next() {
+        for name in unsupported(&pair) {
+            warning!("{name} is not supported, ignored");
+        }
         match pair.as_rule() {
             Rule::newline => content += "\n",
-            Rule::line => content += &gs!(pair),
+            Rule::line => {
+                let mut texts = process_text(pair, &settings);
+                // A citation ending a line is merged with one starting the next line of the
+                // paragraph, as within a block
+                while let Some(Text::Citation(keys)) = texts.last_mut() {
+                    let mut ahead = pairs.clone();
+                    let (Some(newline), Some(next)) = (ahead.next(), ahead.next()) else {
+                        break;
+                    };
+                    if newline.as_rule() != Rule::newline || next.as_rule() != Rule::line {
+                        break;
+                    }
+                    let mut next_texts = process_text(next.clone(), &settings);
+                    let Some(Text::Citation(next_keys)) = next_texts.first() else {
+                        break;
+                    };
+                    keys.push('\n');
+                    keys.push_str(next_keys);
+                    for name in unsupported(&next) {
+                        warning!("{name} is not supported, ignored");
+                    }
+                    next_texts.remove(0);
+                    texts.extend(next_texts);
+                    pairs = ahead;
+                }
+                let line = to_latex(texts, &settings)?;
+                if line.trim_start().starts_with("\\[") {
+                    push_block(&mut content, &line);
+                } else {
+                    content += &line;
+                }
+            }
             Rule::latex_content => content += pair.as_str(),
-            Rule::section => content += &format!("\\section{{{}}}\n", gis!(pair)),
-            Rule::subsection => content += &format!("\\subsection{{{}}}\n", gis!(pair)),
-            Rule::subsubsection => content += &format!("\\subsubsection{{{}}}\n", gis!(pair)),
-            Rule::proof => content += &format!("\\begin{{proof}} {} \\end{{proof}}", gis!(pair)),
+            Rule::set_rule => content += &set_rule(pair, &mut document, &mut settings)?,
+            Rule::let_def => {
+                let mut inner = pair.into_inner();
+                let name = inner.next().map_or("", |p| p.as_str());
+                let params = match inner.next() {
+                    Some(p) if p.as_rule() == Rule::let_params => Args::from_pairs(p.into_inner()),
+                    _ => Args::default(),
+                };
+                if params.positional.len() == 1 {
+                    settings.wrappers.insert(name.into());
+                }
+            }
+            Rule::show_heading => {
+                // Other rules are reported as unsupported
+                match heading_show(pair.into_inner().as_str()) {
+                    Some(HeadingShow::Numbered(numbered)) => {
+                        settings.unnumbered_headings = !numbered
+                    }
+                    Some(HeadingShow::SmallCaps) => settings.smallcaps_headings = true,
+                    None => {}
+                }
+            }
+            Rule::show_raw => {
+                // The code font applies to code blocks and inline code alike, as \texttt
+                if let Some(font) = raw_font(pair.into_inner().as_str()) {
+                    match mono_package(font) {
+                        Some("") => {}
+                        Some(package) => document.preamble.push(package.into()),
+                        None => warning!("unsupported code font {}, the default is kept", font),
+                    }
+                }
+            }
+            Rule::section | Rule::subsection | Rule::subsubsection => {
+                let command = match pair.as_rule() {
+                    Rule::section => "section",
+                    Rule::subsection => "subsection",
+                    _ => "subsubsection",
+                };
+                let star = if settings.unnumbered_headings {
+                    "*"
+                } else {
+                    ""
+                };
+                let mut title = gis!(pair);
+                if settings.smallcaps_headings {
+                    title = format!("\\textsc{{{title}}}");
+                }
+                content += &format!("\\{command}{star}{{{title}}}\n");
+            }
+            Rule::proof => push_block(
+                &mut content,
+                &format!("\\begin{{proof}}\n{}\n\\end{{proof}}", gis!(pair)),
+            ),
             Rule::include => {
                 content += &format!("\\input({})", pair.as_str().replace("typ", "tex"))
             }
             Rule::figure => {
                 let mut fcontent = String::new();
-                let mut caption = String::new();
+                let mut caption = None;
                 let mut label = String::new();
+                let args = Args::from_pairs(pair.clone().into_inner());
+                let placement = match args.get("placement") {
+                    Some(value) => float_placement(value),
+                    None => settings.figure_placement.clone(),
+                };
+                let numbered = args.get("numbering") != Some("none");
                 for p in pair.into_inner() {
                     match p.as_rule() {
                         Rule::fig_content => {
                             fcontent = gis!(p);
                         }
+                        Rule::image_call => {
+                            // Same conversion as the block form [#image(...)]
+                            let call = Text::Raw(format!("#{}", p.as_str()));
+                            fcontent = to_latex(vec![call], &settings)?;
+                        }
+                        Rule::fig_table => {
+                            fcontent = table(p, &settings)?;
+                        }
                         Rule::caption => {
-                            caption = gis!(p);
+                            caption = Some(gis!(p));
                         }
                         Rule::label => {
                             label = format!("\\label{{{}}}", p.as_str());
@@ -141,10 +1334,78 @@ pub fn parse(content: &str, citations: HashSet<String>) -> Result<Document> {
                         _ => {}
                     }
                 }
-                content += &format!(
-                    "\\begin{{figure}}\n{fcontent}\n\\caption{{{caption}}}\n{label}\\end{{figure}}"
+                let fcontent = strip_pagebreaks(fcontent);
+                let placement = placement.map(|p| format!("[{p}]")).unwrap_or_default();
+                // Figures without caption have none in latex either
+                let caption = match caption {
+                    Some(caption) if numbered => format!("\\caption{{{caption}}}\n"),
+                    Some(caption) => format!("\\caption*{{{caption}}}\n"),
+                    None => String::new(),
+                };
+                push_block(
+                    &mut content,
+                    &format!(
+                        "\\begin{{figure}}{placement}\n{fcontent}\n{caption}{label}\\end{{figure}}"
+                    ),
                 )
             }
+            Rule::columns => {
+                let args = Args::from_pairs(pair.clone().into_inner());
+                for (name, _) in args.named.iter().filter(|(name, _)| name != "count") {
+                    warning!("unsupported columns argument {:?}, ignored", name);
+                }
+                // Two columns by default, as in typst
+                let count = match args
+                    .positional
+                    .first()
+                    .map(String::as_str)
+                    .or(args.get("count"))
+                {
+                    Some(count) => count.parse::<u32>().unwrap_or_else(|_| {
+                        warning!("unsupported column count {}, using 2", count);
+                        2
+                    }),
+                    None => 2,
+                };
+                let mut ccontent = String::new();
+                for p in pair.into_inner() {
+                    if p.as_rule() == Rule::text {
+                        ccontent = gs!(p);
+                    }
+                }
+                push_block(
+                    &mut content,
+                    &format!("\\begin{{multicols}}{{{count}}}\n{ccontent}\n\\end{{multicols}}\n"),
+                );
+            }
+            Rule::table => push_block(&mut content, &table(pair, &settings)?),
+            Rule::term_list => {
+                let mut items = String::new();
+                for item in pair.into_inner() {
+                    let mut term = String::new();
+                    let mut description = String::new();
+                    for p in item.into_inner() {
+                        match p.as_rule() {
+                            Rule::term => term = gs!(p),
+                            Rule::line => description = gs!(p),
+                            _ => {}
+                        }
+                    }
+                    // Braces keep brackets in the term from closing the optional argument
+                    items += &format!("\\item[{{{}}}] {}\n", term.trim(), description.trim());
+                }
+                push_block(
+                    &mut content,
+                    &format!("\\begin{{description}}\n{items}\\end{{description}}\n"),
+                );
+            }
+            Rule::raw_block => {
+                let block = match raw_block(&pair, &settings) {
+                    Some(block) => block,
+                    None => to_latex(vec![Text::Raw(pair.as_str().into())], &settings)?,
+                };
+                push_block(&mut content, &block);
+            }
             Rule::theorem => {
                 let mut ttype = String::new();
                 let mut title = String::new();
@@ -167,8 +1428,10 @@ pub fn parse(content: &str, citations: HashSet<String>) -> Result<Document> {
                         _ => {}
                     }
                 }
-                content +=
-                    &format!("\\begin{{{ttype}}}{title} {label}\n{tcontent}\n\\end{{{ttype}}}\n");
+                push_block(
+                    &mut content,
+                    &format!("\\begin{{{ttype}}}{title} {label}\n{tcontent}\n\\end{{{ttype}}}\n"),
+                );
             }
             Rule::header => {
                 for p in pair.into_inner() {
@@ -176,7 +1439,10 @@ pub fn parse(content: &str, citations: HashSet<String>) -> Result<Document> {
                         Rule::my_title => document.title = Some(gis!(p)),
                         Rule::my_abstract => document.abstractt = Some(gis!(p)),
                         Rule::my_name => document.authors = Some(p.as_str().into()),
-                        Rule::my_bib => document.bibliography = Some(gis!(p)),
+                        Rule::my_org => document.organization = Some(gis!(p)),
+                        Rule::my_aff => document.affiliation = Some(gis!(p)),
+                        Rule::my_loc => document.location = Some(gis!(p)),
+                        Rule::my_bib => document.bibliography.push(p.as_str().into()),
                         _ => {}
                     }
                 }
@@ -188,3 +1454,529 @@ pub fn parse(content: &str, citations: HashSet<String>) -> Result<Document> {
     document.content = content;
     Ok(document)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::functions::FUNCTIONS;
+    use crate::math::MATH_FUNCTIONS;
+
+    fn convert(typst: &str) -> String {
+        parse(typst, &Settings::stub()).unwrap().content
+    }
+
+    fn with_citations(keys: &[&str]) -> Settings {
+        Settings {
+            citations: keys.iter().map(|key| key.to_string()).collect(),
+            ..Settings::stub()
+        }
+    }
+
+    #[test]
+    fn header_bibliography_array() {
+        let document = parse(
+            "#show: ams-article.with(\n  bibliography: bibliography((\"a.bib\", \"b.bib\")),\n)\n",
+            &Settings::stub(),
+        )
+        .unwrap();
+        assert_eq!(document.bibliography, ["a.bib", "b.bib"]);
+        let latex = document
+            .to_latex("\\addbibresource{%bibliography%}\n%content%".into())
+            .unwrap();
+        assert!(latex.starts_with("\\addbibresource{a.bib}\n\\addbibresource{b.bib}\n"));
+    }
+
+    #[test]
+    fn math_glued_to_punctuation() {
+        assert_eq!(
+            convert("Let $x$, then $y$.\n"),
+            "Let \\(x\\), then \\(y\\).\n"
+        );
+    }
+
+    #[test]
+    fn columns_count() {
+        let multicols = "\\begin{multicols}{3}\nA\n\\end{multicols}\n";
+        assert_eq!(convert("#columns(3)[A]"), multicols);
+        assert_eq!(convert("#columns(count: 3)[A]"), multicols);
+        assert_eq!(convert("#columns(gutter: 4pt, 3)[A]"), multicols);
+    }
+
+    #[test]
+    fn figure_image_call() {
+        let inline = convert("#figure(image(\"a.png\", width: 50%), caption: [A plot])");
+        let block = convert("#figure([#image(\"a.png\", width: 50%)], caption: [A plot])");
+        assert_eq!(inline, block);
+        assert!(inline.contains("#image(\"a.png\", width: 50%)\n\\caption{A plot}"));
+    }
+
+    #[test]
+    fn figure_dropped_arguments() {
+        let counts =
+            unsupported_rules("#figure(rect[Box], caption: [B], kind: \"plot\", gap: 1em)\n")
+                .unwrap();
+        let names: Vec<&str> = counts.keys().map(String::as_str).collect();
+        assert_eq!(names, ["#figure(...)", "#figure(gap)", "#figure(kind)"]);
+        assert!(
+            unsupported_rules("#figure(image(\"a.png\"), placement: top)\n")
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn adjacent_figures_separated() {
+        let latex = convert("#figure([A], caption: [a])\n#figure([B], caption: [b])\n");
+        assert!(
+            latex.contains("\\end{figure}\n\n\\begin{figure}"),
+            "{latex}"
+        );
+    }
+
+    #[test]
+    fn nested_dropped_constructs() {
+        let counts = unsupported_rules(
+            "#figure(table(columns: 2, inset: 4pt, [a], [b]), caption: [T])\n#theorem[See #context query(<a>)]\n",
+        )
+        .unwrap();
+        assert_eq!(counts.get("#table(inset)"), Some(&1));
+        assert_eq!(counts.get("#context"), Some(&1));
+    }
+
+    #[test]
+    fn title_suppressed() {
+        let document = parse(
+            "#show: ams-article.with(\n  title: [My title],\n)\n#set document(title: none)\n",
+            &Settings::stub(),
+        )
+        .unwrap();
+        assert_eq!(document.title, None);
+        let latex = document
+            .to_latex(include_str!("templates/default.tex").into())
+            .unwrap();
+        assert!(!latex.contains("\\maketitle"));
+        assert!(!latex.contains("\\title{"));
+    }
+
+    #[test]
+    fn horizontal_rule() {
+        assert_eq!(
+            convert("#line(length: 50%, stroke: 2pt + red)\n"),
+            "\\noindent\\rule{0.5\\linewidth}{2pt}\n"
+        );
+    }
+
+    #[test]
+    fn citation_in_footnote() {
+        let document = parse("Text#footnote[See @knuth.]\n", &with_citations(&["knuth"])).unwrap();
+        assert_eq!(document.content, "Text\\footnote{See \\cite{knuth}.}\n");
+    }
+
+    #[test]
+    fn heading_supplement() {
+        let document = parse("#set heading(supplement: [Chapter])\n", &Settings::stub()).unwrap();
+        assert!(
+            document
+                .preamble
+                .contains(&"\\crefname{section}{Chapter}{Chapter}".to_string())
+        );
+        let latex = document.to_latex("%preamble%\n%content%".into()).unwrap();
+        assert!(latex.starts_with("\\usepackage{cleveref}\n"));
+    }
+
+    #[test]
+    fn citations_across_lines() {
+        let settings = with_citations(&["a", "b"]);
+        let document = parse("#proof[See @a\n@b for more.]\n", &settings).unwrap();
+        assert!(document.content.contains("See \\cite{a,b} for more."));
+        let document = parse("#proof[See @a\n\n@b for more.]\n", &settings).unwrap();
+        assert!(
+            document
+                .content
+                .contains("See \\cite{a}\n\n\\cite{b} for more.")
+        );
+    }
+
+    #[test]
+    fn context_expressions() {
+        let latex = convert(
+            "Page #context counter(page).display() of @intro, #context counter(heading).at(<intro>).first() #context query(<a>).\n",
+        );
+        assert_eq!(
+            latex,
+            "Page \\thepage of \\autoref{intro}, \\ref{intro} .\n"
+        );
+    }
+
+    #[test]
+    fn line_numbers() {
+        let document = parse("#set par.line(numbering: \"1\")\nText\n", &Settings::stub()).unwrap();
+        assert_eq!(document.content, "\\linenumbers\nText\n");
+        let latex = document.to_latex("%preamble%\n%content%".into()).unwrap();
+        assert!(latex.starts_with("\\usepackage{lineno}\n"));
+    }
+
+    #[test]
+    fn template_import_supported() {
+        let template = "#import \"@preview/unequivocal-ams:0.1.2\": ams-article, theorem, proof\n\n#show: ams-article.with(\n  title: [A Fluid Dynamic Model],\n  abstract: [Some abstract.],\n)\n\n= Introduction\nSome text.\n";
+        assert!(unsupported_rules(template).unwrap().is_empty());
+        let rules = unsupported_rules("#show link: underline\nText.\n").unwrap();
+        assert_eq!(rules.get("#show"), Some(&1));
+    }
+
+    #[test]
+    fn numeric_font_weight() {
+        assert_eq!(
+            convert("#set text(weight: 700)\nBold text.\n"),
+            "\\bfseries\nBold text.\n"
+        );
+        assert_eq!(convert("#set text(weight: 400)\nText.\n"), "Text.\n");
+    }
+
+    #[test]
+    fn set_align_paragraphs() {
+        assert_eq!(
+            convert(
+                "#set align(center)\nFirst paragraph.\n\nSecond paragraph.\n#set align(start)\nThird.\n"
+            ),
+            "\\centering\nFirst paragraph.\n\nSecond paragraph.\n\\justifying\nThird.\n"
+        );
+    }
+
+    #[test]
+    fn math_and_equation_reference() {
+        assert_eq!(
+            convert("We have $x = 1$ and @eq:1 here.\n"),
+            "We have \\(x = 1\\) and \\autoref{eq:1} here.\n"
+        );
+    }
+
+    #[test]
+    fn structured_authors() {
+        let document = parse(
+            "#set document(author: ((name: \"A\", affiliation: \"X\", email: \"a@x\"), (name: \"B\", affiliation: \"Y\")))\nText\n",
+            &Settings::stub(),
+        )
+        .unwrap();
+        let latex = document
+            .to_latex("%preamble%\n\\author{%authors%}\n%content%".into())
+            .unwrap();
+        assert_eq!(
+            latex,
+            "\\usepackage{authblk}\n\\author[1]{A\\thanks{a@x}}\n\\author[2]{B}\n\\affil[1]{X}\n\\affil[2]{Y}\nText\n"
+        );
+    }
+
+    #[test]
+    fn table_mid_hline() {
+        assert_eq!(
+            convert("#table(columns: 2, stroke: none, [a], [b], table.hline(), [c], [d])\n"),
+            "\\begin{tabular}{ll}\na & b \\\\\n\\hline\nc & d \\\\\n\\end{tabular}\n"
+        );
+    }
+
+    #[test]
+    fn page_fill() {
+        let document = parse(
+            "#set page(fill: rgb(\"#eeeeee\"))\nText\n",
+            &Settings::stub(),
+        )
+        .unwrap();
+        assert_eq!(document.preamble, ["\\pagecolor[HTML]{EEEEEE}"]);
+        assert!(
+            parse("#set page(fill: white)\nText\n", &Settings::stub())
+                .unwrap()
+                .preamble
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn let_wrapper() {
+        let typst = "#let note(body) = block(body)\n#note[Some *remark*.]\n";
+        let latex = convert(typst);
+        assert!(latex.starts_with("\\begin{quote}"), "{latex}");
+        assert!(latex.ends_with("\\end{quote}\n"), "{latex}");
+        if let Some(settings) = Settings::pandoc() {
+            let latex = parse(typst, &settings).unwrap().content;
+            assert!(latex.contains("\\textbf{remark}"), "{latex}");
+        }
+        let counts = unsupported_rules("#let note(body) = block(body)\n").unwrap();
+        assert!(counts.is_empty(), "{counts:?}");
+        let counts = unsupported_rules("#let pair(a, b) = block(a)\n").unwrap();
+        assert_eq!(counts.get("#let"), Some(&1));
+    }
+
+    #[test]
+    fn raw_block_verbatim() {
+        let settings = Settings {
+            preserve_raw_blocks: true,
+            ..Settings::stub()
+        };
+        let document = parse("```tex\n\\newcommand{\\a}{}\n{x}_\\\n```\n", &settings).unwrap();
+        assert_eq!(
+            document.content,
+            "\\begin{verbatim}\n\\newcommand{\\a}{}\n{x}_\\\n\\end{verbatim}\n"
+        );
+        let document = parse("#proof[\nBy\n```\n{x}_\\\n```\n]\n", &settings).unwrap();
+        assert!(
+            document
+                .content
+                .contains("\\begin{verbatim}\n{x}_\\\n\\end{verbatim}"),
+            "{}",
+            document.content
+        );
+    }
+
+    #[test]
+    fn small_caps_heading() {
+        assert_eq!(
+            convert("= #smallcaps[Intro]\nText\n"),
+            "\\section{\\textsc{Intro}}\n\nText\n"
+        );
+    }
+
+    #[test]
+    fn dot_leaders() {
+        assert_eq!(convert("Title #repeat[.] 42\n"), "Title \\dotfill 42\n");
+    }
+
+    #[test]
+    fn biblatex_citations() {
+        let settings = Settings {
+            biblatex: true,
+            ..with_citations(&["a", "b"])
+        };
+        let document = parse("See @a and #cite(<b>, form: \"prose\").\n", &settings).unwrap();
+        assert_eq!(document.content, "See \\parencite{a} and \\textcite{b}.\n");
+    }
+
+    #[test]
+    fn pagebreak_in_figure() {
+        assert_eq!(
+            convert("#figure([Body #pagebreak() more], caption: [C])\n"),
+            "\\begin{figure}\nBody more\n\\caption{C}\n\\end{figure}\n"
+        );
+    }
+
+    #[test]
+    fn term_markup() {
+        let typst = "/ *Bold* term [x]: description\n";
+        let latex = convert(typst);
+        assert!(
+            latex.starts_with("\\begin{description}\n\\item[{"),
+            "{latex}"
+        );
+        assert!(
+            latex.ends_with("}] description\n\\end{description}\n"),
+            "{latex}"
+        );
+        // The markup of the term is converted by pandoc
+        if let Some(settings) = Settings::pandoc() {
+            let latex = parse(typst, &settings).unwrap().content;
+            assert!(latex.contains("\\item[{\\textbf{Bold} term"), "{latex}");
+        }
+    }
+
+    #[test]
+    fn line_and_text_delimiters() {
+        // A heading is a line: its parenthesis ends at the newline and is kept as a lone delimiter
+        assert_eq!(
+            convert("= Intro (draft\nText) more\n"),
+            "\\section{Intro (draft}\n\nText) more\n"
+        );
+        assert_eq!(convert("A (b\nc) d [e] \"f\"\n"), "A (b\nc) d [e] \"f\"\n");
+        // A content block is text: its parenthesis spans the newline
+        assert_eq!(
+            convert("#text(fill: red)[a (b\nc) d]\n"),
+            "#text(fill: red)[a (b\nc) d]\n"
+        );
+    }
+
+    #[test]
+    fn heading_numbering_suffix() {
+        let commands = heading_numbering("1)").unwrap();
+        assert_eq!(
+            commands,
+            [
+                "\\renewcommand{\\thesection}{\\arabic{section})}",
+                "\\renewcommand{\\thesubsection}{\\arabic{section})\\arabic{subsection})}",
+                "\\renewcommand{\\thesubsubsection}{\\arabic{section})\\arabic{subsection})\\arabic{subsubsection})}",
+            ]
+        );
+        assert!(heading_numbering("1.").unwrap().is_empty());
+        assert!(heading_numbering("1.1").unwrap().is_empty());
+    }
+
+    #[test]
+    fn headerless_document() {
+        let latex = parse("= Intro\nText\n", &Settings::stub())
+            .unwrap()
+            .to_latex(include_str!("templates/default.tex").into())
+            .unwrap();
+        for artifact in [
+            "\\title{",
+            "\\maketitle",
+            "\\begin{abstract}",
+            "\\printbibliography",
+        ] {
+            assert!(!latex.contains(artifact), "{artifact}");
+        }
+        assert!(latex.contains("\\begin{document}") && latex.contains("\\section{Intro}"));
+    }
+
+    #[test]
+    fn rect_with_text() {
+        let latex = convert("#rect[Boxed *text*]\n");
+        assert!(latex.starts_with("\\fbox{"), "{latex}");
+        assert!(latex.ends_with("}\n"), "{latex}");
+        if let Some(settings) = Settings::pandoc() {
+            let latex = parse("#rect[Boxed *text*]\n", &settings).unwrap().content;
+            assert!(latex.contains("\\fbox{Boxed \\textbf{text}}"), "{latex}");
+        }
+    }
+
+    #[test]
+    fn first_line_indent_all() {
+        let document = parse(
+            "#set par(first-line-indent: (amount: 1em, all: true))\n= H\nText\n",
+            &Settings::stub(),
+        )
+        .unwrap();
+        assert_eq!(
+            document.content,
+            "\\setlength{\\parindent}{1em}\n\\section{H}\n\nText\n"
+        );
+        assert_eq!(document.preamble, ["\\usepackage{indentfirst}"]);
+    }
+
+    #[test]
+    fn code_font_show_rule() {
+        let document = parse(
+            "#show raw: set text(font: \"Fira Code\")\nText `code`\n",
+            &Settings::stub(),
+        )
+        .unwrap();
+        assert_eq!(document.content, "Text `code`\n");
+        assert_eq!(document.preamble, ["\\usepackage[scale=0.85]{FiraMono}"]);
+        assert!(
+            unsupported_rules("#show raw: set text(font: \"Fira Code\")\n")
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn citations_across_top_level_lines() {
+        let settings = with_citations(&["a", "b", "c"]);
+        let document = parse("See @a\n@b\n@c for more.\n", &settings).unwrap();
+        assert_eq!(document.content, "See \\cite{a,b,c} for more.\n");
+        let document = parse("See @a\n\n@b for more.\n", &settings).unwrap();
+        assert_eq!(document.content, "See \\cite{a}\n\n\\cite{b} for more.\n");
+    }
+
+    #[test]
+    fn table_header_and_cells() {
+        assert_eq!(
+            convert(
+                "#table(columns: 2, stroke: none, table.header([A], [*B*]), [a], [b], table.cell(colspan: 2)[c $x$])\n"
+            ),
+            "\\begin{tabular}{ll}\nA & *B* \\\\\na & b \\\\\n\\multicolumn{2}{l}{c \\(x\\)} \\\\\n\\end{tabular}\n"
+        );
+        let rules =
+            unsupported_rules("#table(columns: 2, table.cell(rowspan: 2)[a], [b], [c])\n").unwrap();
+        assert_eq!(rules.get("table.cell(rowspan)"), Some(&1));
+    }
+
+    #[test]
+    fn one_line_theorems() {
+        let expected =
+            "\\begin{theorem} \nA\n\\end{theorem}\n\n\\begin{lemma} \nB\n\\end{lemma}\n\n";
+        assert_eq!(convert("#theorem[A]\n\n#lemma[B]\n"), expected);
+        assert_eq!(
+            convert("#theorem[Title\nA]\n"),
+            "\\begin{theorem}[Title] \nA\n\\end{theorem}\n\n"
+        );
+    }
+
+    #[test]
+    fn listed_features_are_handled() {
+        // An example per entry of the lists printed by --list-features, in the same order
+        let constructs = [
+            "= A\n== B\n=== C\n",
+            "#theorem[A]\n#lemma[B]\n#corollary[C]\n#proposition[D]\n#definition[E]\n#example[F]\n#property[G]\n",
+            "#proof[A]\n",
+            "#figure(image(\"a.png\"), caption: [A])\n",
+            "#table(columns: 2, [a], [b])\n",
+            "#columns(2)[A]\n",
+            "#grid(columns: 2, [a], [b])\n",
+            "```\ncode\n```\n",
+            "/ Term: description\n",
+            "#include(\"a.typ\")\n",
+            "$x + 1$\n",
+            "See @a.\n",
+            "#context counter(heading).display()\n",
+            "#set align(center)\n",
+            "#show heading: smallcaps\n",
+            "#show raw: set text(font: \"Fira Mono\")\n",
+            "#let note(body) = block(body)\n#note[A]\n",
+            "#show: ams-article.with(title: [A])\n",
+            "/* BEGIN TEX\n\\relax\nEND TEX */\n",
+            "// BEGIN NO TEX\nA\n// END NO TEX\n",
+        ];
+        assert_eq!(constructs.len(), CONSTRUCTS.len());
+        for (construct, typst) in CONSTRUCTS.iter().zip(constructs) {
+            let unsupported = unsupported_rules(typst).unwrap();
+            assert!(unsupported.is_empty(), "{construct}: {unsupported:?}");
+            parse(typst, &Settings::stub()).unwrap();
+        }
+
+        let functions = [
+            "#text(weight: \"bold\")[A]",
+            "#quote(block: true)[A]",
+            "#line(length: 100%)",
+            "#footnote[A]",
+            "#smallcaps[A]",
+            "#strong[A]",
+            "#cite(<a>)",
+            "#pagebreak()",
+            "#repeat[.]",
+            "#block[A]",
+            "#rect[A]",
+            "#circle[A]",
+            "#v(1em)",
+            "#h(1em)",
+        ];
+        assert_eq!(functions.len(), FUNCTIONS.len());
+        for (name, typst) in FUNCTIONS.iter().zip(functions) {
+            assert!(
+                typst.starts_with(&format!("#{name}(")) || typst.starts_with(&format!("#{name}["))
+            );
+            let latex = convert(&format!("Before {typst} after\n"));
+            assert!(!latex.contains(&format!("#{name}")), "{name}: {latex}");
+        }
+
+        let math = [
+            "floor(x)",
+            "ceil(x)",
+            "round(x)",
+            "mat(1, 2; 3, 4, augment: 1)",
+            "underbrace(x, 1)",
+            "overbrace(x, 1)",
+            "cases(delim: \"[\", 1, 0)",
+            "vec(a, b)",
+            "limits(sum)_i",
+            "scripts(sum)_i",
+            "cancel(x)",
+            "attach(x, t: 1)",
+            "dd(x)",
+            "diff(f, x)",
+        ];
+        assert_eq!(math.len(), MATH_FUNCTIONS.len());
+        for (name, typst) in MATH_FUNCTIONS.iter().zip(math) {
+            assert!(typst.starts_with(&format!("{name}(")));
+            let latex = convert(&format!("${typst}$\n"));
+            assert!(!latex.contains(&format!("{name}(")), "{name}: {latex}");
+        }
+    }
+}
@@ -1,33 +1,301 @@
-// TODO use organization, affiliation and location
+use crate::warnings::warning;
+use anyhow::Result;
+
+/// Packages required by the environments and commands emitted during the conversion.
+const PACKAGES: [(&str, &str); 21] = [
+    ("\\begin{multicols}", "multicol"),
+    ("\\crefname", "cleveref"),
+    ("\\begin{minted}", "minted"),
+    ("\\linenumbers", "lineno"),
+    ("\\nolinenumbers", "lineno"),
+    ("\\justifying", "ragged2e"),
+    ("\\pagecolor", "xcolor"),
+    ("\\RL{", "bidi"),
+    ("\\LR{", "bidi"),
+    ("\\setRTL", "bidi"),
+    ("\\setLTR", "bidi"),
+    ("\\caption*", "caption"),
+    ("\\enquote", "csquotes"),
+    ("\\begin{displayquote}", "csquotes"),
+    ("\\color", "xcolor"),
+    ("\\cancel", "cancel"),
+    ("\\bcancel", "cancel"),
+    ("\\xcancel", "cancel"),
+    ("\\CancelColor", "cancel"),
+    ("\\tikz", "tikz"),
+    ("\\prescript", "mathtools"),
+];
+
+/// Packages of a template compiled with XeLaTeX, which bidi requires.
+const XELATEX_PACKAGES: [&str; 2] = ["{fontspec}", "{polyglossia}"];
+
+/// Commands defining labels, then commands referring to labels or citing bib entries.
+const LABEL_COMMANDS: [&str; 1] = ["label"];
+const REFERENCE_COMMANDS: [&str; 11] = [
+    "ref",
+    "autoref",
+    "pageref",
+    "cref",
+    "cite",
+    "parencite",
+    "textcite",
+    "citeauthor",
+    "citeyear",
+    "fullcite",
+    "nocite",
+];
+
+/// An author of `#set document(author: ...)` given as a dictionary, converted with authblk.
+#[derive(Debug, Clone, Default)]
+pub struct Author {
+    pub name: String,
+    pub affiliation: Option<String>,
+    pub email: Option<String>,
+}
 
 #[derive(Debug, Clone, Default)]
 pub struct Document {
     pub title: Option<String>,
     pub authors: Option<String>,
+    /// Authors with affiliations, replacing the template line holding %authors%.
+    pub structured_authors: Vec<Author>,
     pub date: Option<String>,
     pub abstractt: Option<String>,
-    pub bibliography: Option<String>,
+    pub bibliography: Vec<String>,
     pub organization: Option<String>,
     pub affiliation: Option<String>,
     pub location: Option<String>,
+    /// Latex commands for the preamble, e.g. settings from set rules.
+    pub preamble: Vec<String>,
     pub content: String,
 }
 
 impl Document {
-    pub fn to_latex(&self, template: String) -> String {
+    /// Fills the template, which must hold %content%. Fields without placeholder are reported.
+    pub fn to_latex(&self, template: String) -> Result<String> {
+        if !template.contains("%content%") {
+            anyhow::bail!("The template has no %content% placeholder for the converted document");
+        }
         let title = self.title.as_deref().unwrap_or("");
         let authors = self.authors.as_deref().unwrap_or("");
         let abstract_text = self.abstractt.as_deref().unwrap_or("");
-        let bibliography = self.bibliography.as_deref().unwrap_or("");
+        let organization = self.organization.as_deref().unwrap_or("");
+        let affiliation = self.affiliation.as_deref().unwrap_or("");
+        let location = self.location.as_deref().unwrap_or("");
         let date = self.date.as_deref().unwrap_or(r"\today");
         let content = &self.content;
+        let preamble = self.preamble(&template);
 
-        template
+        let fields = [
+            ("title", self.title.is_some()),
+            (
+                "authors",
+                self.authors.is_some() || !self.structured_authors.is_empty(),
+            ),
+            ("abstract", self.abstractt.is_some()),
+            ("organization", self.organization.is_some()),
+            ("affiliation", self.affiliation.is_some()),
+            ("location", self.location.is_some()),
+            ("date", self.date.is_some()),
+            ("bibliography", !self.bibliography.is_empty()),
+            ("preamble", !preamble.is_empty()),
+        ];
+        for (field, set) in fields {
+            if set && !template.contains(&format!("%{field}%")) {
+                warning!("the template has no %{field}% placeholder, the {field} field is dropped");
+            }
+        }
+        // bidi, loaded here for the text direction, fails with pdfLaTeX
+        if preamble.contains("\\usepackage{bidi}")
+            && template.contains("\\documentclass")
+            && !XELATEX_PACKAGES.iter().any(|p| template.contains(p))
+        {
+            warning!(
+                "right-to-left text requires XeLaTeX, which the template does not seem to use"
+            );
+        }
+
+        Ok(self
+            .expand_bibliography(&self.expand_authors(&self.conditional_lines(&template)))
+            .replace("%preamble%", &preamble)
             .replace("%title%", title)
             .replace("%authors%", authors)
             .replace("%abstract%", abstract_text)
-            .replace("%bibliography%", bibliography)
+            .replace("%organization%", organization)
+            .replace("%affiliation%", affiliation)
+            .replace("%location%", location)
             .replace("%date%", date)
-            .replace("%content%", content)
+            .replace("%content%", content))
+    }
+
+    /// Labels defined and keys referred to in the content, one `def name` or `ref name` per line,
+    /// in order of first appearance.
+    pub fn labels(&self) -> String {
+        let mut entries: Vec<String> = Vec::new();
+        for (index, _) in self.content.match_indices('\\') {
+            let rest = &self.content[index + 1..];
+            let name_len = rest
+                .find(|c: char| !c.is_ascii_alphabetic())
+                .unwrap_or(rest.len());
+            let tag = match &rest[..name_len] {
+                name if LABEL_COMMANDS.contains(&name) => "def",
+                name if REFERENCE_COMMANDS.contains(&name) => "ref",
+                _ => continue,
+            };
+            // Optional arguments come first, e.g. \cite[p.~3]{key}
+            let mut args = &rest[name_len..];
+            while let Some(optional) = args.strip_prefix('[') {
+                args = optional.split_once(']').map_or("", |(_, after)| after);
+            }
+            let Some((keys, _)) = args.strip_prefix('{').and_then(|a| a.split_once('}')) else {
+                continue;
+            };
+            for key in keys.split(',').map(str::trim).filter(|k| !k.is_empty()) {
+                let entry = format!("{tag} {key}");
+                if !entries.contains(&entry) {
+                    entries.push(entry);
+                }
+            }
+        }
+        entries.iter().map(|entry| format!("{entry}\n")).collect()
+    }
+
+    // Lines of the template that only make sense when a field is set, e.g. \maketitle without title,
+    // so that a document without header gives neither an empty title nor an empty abstract. The author
+    // details each take their own line.
+    fn conditional_lines(&self, template: &str) -> String {
+        template
+            .split_inclusive('\n')
+            .filter(|line| {
+                let line = line.trim();
+                let needs_title = line.contains("%title%") || line == "\\maketitle";
+                let needs_abstract = line.contains("%abstract%")
+                    || line == "\\begin{abstract}"
+                    || line == "\\end{abstract}";
+                let needs_bibliography = line == "\\printbibliography";
+                let details = [
+                    ("%organization%", &self.organization),
+                    ("%affiliation%", &self.affiliation),
+                    ("%location%", &self.location),
+                ];
+                details
+                    .iter()
+                    .all(|(placeholder, field)| !line.contains(placeholder) || field.is_some())
+                    && (!needs_title || self.title.is_some())
+                    && (!needs_abstract || self.abstractt.is_some())
+                    && (!needs_bibliography || !self.bibliography.is_empty())
+            })
+            .collect()
+    }
+
+    // Packages needed by the content and not loaded by the template, followed by the preamble commands
+    fn preamble(&self, template: &str) -> String {
+        let mut lines = Vec::new();
+        for (command, package) in PACKAGES {
+            let line = format!("\\usepackage{{{}}}", package);
+            let loaded = template.contains(&format!("{{{}}}", package)) || lines.contains(&line);
+            let used =
+                self.content.contains(command) || self.preamble.iter().any(|l| l.contains(command));
+            if used && !loaded {
+                lines.push(line);
+            }
+        }
+        if !self.structured_authors.is_empty() && !template.contains("{authblk}") {
+            lines.push("\\usepackage{authblk}".into());
+        }
+        lines.extend(self.preamble.iter().cloned());
+        lines.join("\n")
+    }
+
+    // With structured authors, the line holding the authors placeholder becomes the authblk commands,
+    // e.g. \author[1]{A} and \affil[1]{X}, the affiliations being numbered in order of appearance.
+    fn expand_authors(&self, template: &str) -> String {
+        if self.structured_authors.is_empty() {
+            return template.to_string();
+        }
+        let mut affiliations: Vec<&str> = Vec::new();
+        let mut authors = String::new();
+        for author in &self.structured_authors {
+            let thanks = author
+                .email
+                .as_ref()
+                .map(|email| format!("\\thanks{{{email}}}"))
+                .unwrap_or_default();
+            match author.affiliation.as_deref() {
+                Some(affiliation) => {
+                    let index = match affiliations.iter().position(|a| *a == affiliation) {
+                        Some(index) => index + 1,
+                        None => {
+                            affiliations.push(affiliation);
+                            affiliations.len()
+                        }
+                    };
+                    authors += &format!("\\author[{index}]{{{}{thanks}}}\n", author.name);
+                }
+                None => authors += &format!("\\author{{{}{thanks}}}\n", author.name),
+            }
+        }
+        for (index, affiliation) in affiliations.iter().enumerate() {
+            authors += &format!("\\affil[{}]{{{affiliation}}}\n", index + 1);
+        }
+
+        template
+            .split_inclusive('\n')
+            .map(|line| {
+                if line.contains("%authors%") {
+                    authors.as_str()
+                } else {
+                    line
+                }
+            })
+            .collect()
+    }
+
+    // A line holding the bibliography placeholder is repeated once per bibliography file,
+    // e.g. one \addbibresource per file with biblatex.
+    fn expand_bibliography(&self, template: &str) -> String {
+        let mut result = String::new();
+        for line in template.split_inclusive('\n') {
+            if line.contains("%bibliography%") {
+                for bib in &self.bibliography {
+                    result.push_str(&line.replace("%bibliography%", bib));
+                }
+            } else {
+                result.push_str(line);
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn template_without_content() {
+        let document = Document {
+            content: "Text\n".into(),
+            ..Default::default()
+        };
+        let error = document
+            .to_latex("\\begin{document}\n\\end{document}".into())
+            .unwrap_err();
+        assert!(error.to_string().contains("%content%"), "{error}");
+        assert_eq!(document.to_latex("[%content%]".into()).unwrap(), "[Text\n]");
+    }
+
+    #[test]
+    fn default_template_author_details() {
+        let template = include_str!("templates/default.tex");
+        let document = Document {
+            organization: Some("Org".into()),
+            location: Some("Paris".into()),
+            content: "Text\n".into(),
+            ..Default::default()
+        };
+        let latex = document.to_latex(template.into()).unwrap();
+        assert!(latex.contains("Org\\par\nParis\\par\n"), "{latex}");
+        assert!(!latex.contains("%affiliation%"), "{latex}");
     }
 }
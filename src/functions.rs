@@ -0,0 +1,449 @@
+use crate::args::{Args, array_items, length_to_latex, unquote};
+use crate::settings::Settings;
+use crate::text::cite_command;
+use crate::warnings::warning;
+
+// Conversion of the typst functions matched by the func rule. The body of a function is converted
+// as usual and wrapped between the latex produced here. Arguments that are not handled are passed on
+// to pandoc by keeping the function call around the body.
+
+/// Functions converted by convert_func, also listed by the func_name rule of the grammar.
+pub const FUNCTIONS: [&str; 14] = [
+    "text",
+    "quote",
+    "line",
+    "footnote",
+    "smallcaps",
+    "strong",
+    "cite",
+    "pagebreak",
+    "repeat",
+    "block",
+    "rect",
+    "circle",
+    "v",
+    "h",
+];
+
+/// Latex wrapping the body of a function call.
+#[derive(Debug, Default)]
+pub struct Wrapper {
+    pub open: String,
+    pub close: String,
+    /// Arguments left to pandoc, which receives the call with these arguments only.
+    pub remaining: Args,
+    /// The body is replaced by the latex, e.g. the dot of a repeated dot leader.
+    pub drop_body: bool,
+}
+
+impl Wrapper {
+    fn wrap(&mut self, open: &str, close: &str) {
+        self.open.push_str(open);
+        self.close.insert_str(0, close);
+    }
+}
+
+/// Negates a typst length, e.g. `-0.2em` becomes `0.2em`.
+fn negate_length(length: &str) -> String {
+    match length.strip_prefix('-') {
+        Some(positive) => positive.trim().to_string(),
+        None => format!("-{}", length.trim_start_matches('+').trim()),
+    }
+}
+
+/// Latex series declaration of a typst font weight, e.g. `700` or `"bold"` gives `\\bfseries`.
+/// Numeric weights go to the nearest series among light, medium and bold.
+pub fn weight_series(weight: &str) -> Option<&'static str> {
+    const LIGHT: &str = "\\fontseries{l}\\selectfont";
+    let weight = match unquote(weight).unwrap_or(weight) {
+        "thin" | "extralight" | "light" => 300,
+        "regular" | "medium" => 400,
+        "semibold" | "bold" | "extrabold" | "black" => 700,
+        number => number.parse::<u16>().ok()?,
+    };
+    Some(match weight {
+        ..350 => LIGHT,
+        350..600 => "\\mdseries",
+        _ => "\\bfseries",
+    })
+}
+
+/// Word spacing of a typst text spacing, e.g. `150%` gives `\\spaceskip=1.5\\fontdimen2\\font ...`.
+/// Percentages scale the spaces of the font, absolute lengths keep the stretch of the font.
+pub fn word_spacing(spacing: &str) -> Option<String> {
+    const STRETCH: &str = "plus \\fontdimen3\\font minus \\fontdimen4\\font";
+    if let Some(percent) = spacing.trim().strip_suffix('%') {
+        let ratio = percent.trim().parse::<f64>().ok()? / 100.0;
+        return Some(format!(
+            "\\spaceskip={ratio}\\fontdimen2\\font plus {ratio}\\fontdimen3\\font minus {ratio}\\fontdimen4\\font\\relax"
+        ));
+    }
+    let length = length_to_latex(spacing, "")?;
+    warning!(
+        "word spacing {} approximated, the spaces stretch as in the font",
+        spacing
+    );
+    Some(format!("\\spaceskip={length} {STRETCH}\\relax"))
+}
+
+fn text(args: &Args) -> Wrapper {
+    let mut wrapper = Wrapper::default();
+    wrapper.remaining.positional = args.positional.clone();
+    for (name, value) in &args.named {
+        match name.as_str() {
+            // Typst shifts the baseline down for positive values, \raisebox raises the text
+            "baseline" => wrapper.wrap(&format!("\\raisebox{{{}}}{{", negate_length(value)), "}"),
+            "weight" => match weight_series(value) {
+                Some(series) => wrapper.wrap(&format!("{{{series} "), "}"),
+                None => wrapper.remaining.named.push((name.clone(), value.clone())),
+            },
+            "spacing" => match word_spacing(value) {
+                Some(spacing) => wrapper.wrap(&format!("{{{spacing} "), "}"),
+                None => wrapper.remaining.named.push((name.clone(), value.clone())),
+            },
+            "number-type" if unquote(value) == Some("old-style") => {
+                wrapper.wrap("\\oldstylenums{", "}")
+            }
+            "number-type" if unquote(value) == Some("lining") => {}
+            "dir" => match value.as_str() {
+                "rtl" => wrapper.wrap("\\RL{", "}"),
+                "ltr" => wrapper.wrap("\\LR{", "}"),
+                _ => warning!("unsupported text direction {}, ignored", value),
+            },
+            "features" => {
+                for feature in array_items(value) {
+                    // Features are either strings or dictionary entries, e.g. (smcp: 1)
+                    let (feature, enabled) = match feature.split_once(':') {
+                        Some((feature, value)) => (feature.trim(), value.trim() != "0"),
+                        None => (unquote(feature).unwrap_or(feature), true),
+                    };
+                    match feature {
+                        _ if !enabled => {}
+                        "smcp" => wrapper.wrap("\\textsc{", "}"),
+                        "onum" => wrapper.wrap("\\oldstylenums{", "}"),
+                        _ => warning!("unsupported OpenType feature {:?}, ignored", feature),
+                    }
+                }
+            }
+            _ => wrapper.remaining.named.push((name.clone(), value.clone())),
+        }
+    }
+    wrapper
+}
+
+/// Strong emphasis, e.g. `#strong(delta: 0)[...]` keeps the regular weight. Any other delta, the
+/// default of 300 included, gives \\textbf.
+fn strong(args: &Args) -> Option<Wrapper> {
+    let mut delta = 300;
+    for (name, value) in &args.named {
+        match name.as_str() {
+            "delta" => delta = value.trim().parse::<i32>().ok()?,
+            _ => warning!("unsupported strong argument {:?}, ignored", name),
+        }
+    }
+    let mut wrapper = Wrapper::default();
+    if delta != 0 {
+        wrapper.wrap("\\textbf{", "}");
+    }
+    Some(wrapper)
+}
+
+/// Page break, e.g. `#pagebreak(to: "odd")` becomes `\\cleardoublepage`. Latex has no weak page
+/// break, a weak one is converted like the others.
+fn pagebreak(args: &Args) -> Wrapper {
+    if args.get("weak") == Some("true") {
+        warning!("weak page breaks are not supported, converted to a plain page break");
+    }
+    Wrapper {
+        open: match args.get("to").and_then(unquote) {
+            Some("odd" | "even") => "\\cleardoublepage".into(),
+            _ => "\\clearpage".into(),
+        },
+        ..Default::default()
+    }
+}
+
+/// Vertical or horizontal spacing, e.g. `#v(50%)` becomes `\\vspace{0.5\\textheight}`.
+fn spacing(args: &Args, command: &str, relative_to: &str, fill: &str) -> Option<Wrapper> {
+    let amount = args
+        .positional
+        .first()
+        .map(String::as_str)
+        .or(args.get("amount"))?;
+    let latex = if amount.ends_with("fr") {
+        fill.to_string()
+    } else if let Some(length) = length_to_latex(amount, relative_to) {
+        format!("{command}{{{length}}}")
+    } else {
+        warning!("unsupported spacing {}, left to pandoc", amount);
+        return None;
+    };
+    Some(Wrapper {
+        open: latex,
+        ..Default::default()
+    })
+}
+
+/// Block with the spacing around it, e.g. `#block(above: 1em)[...]` becomes `\\vspace{1em}` before the body.
+/// The other arguments are left to pandoc.
+fn block(args: &Args) -> Wrapper {
+    let mut wrapper = Wrapper::default();
+    wrapper.remaining.positional = args.positional.clone();
+    let (mut above, mut below) = (None, None);
+    for (name, value) in &args.named {
+        let length = length_to_latex(value, "\\textheight");
+        match name.as_str() {
+            "above" if length.is_some() => above = length,
+            "below" if length.is_some() => below = length,
+            "spacing" if length.is_some() => (above, below) = (length.clone(), length),
+            _ => wrapper.remaining.named.push((name.clone(), value.clone())),
+        }
+    }
+    if let Some(above) = above {
+        wrapper.open = format!("\\vspace{{{above}}}");
+    }
+    if let Some(below) = below {
+        wrapper.close = format!("\\vspace{{{below}}}");
+    }
+    wrapper
+}
+
+/// Shape around content, e.g. `#rect[...]` becomes `\\fbox{...}` and `#circle[...]` a circled tikz node.
+/// Styled shapes are drawn as a plain \\fbox.
+fn shape(name: &str, args: &Args) -> Wrapper {
+    let mut wrapper = Wrapper::default();
+    if name == "circle" && args.named.is_empty() && args.positional.is_empty() {
+        wrapper.wrap(
+            "\\tikz[baseline=(shape.base)]\\node[draw, circle, inner sep=1pt] (shape) {",
+            "};",
+        );
+        return wrapper;
+    }
+    if !args.named.is_empty() || !args.positional.is_empty() {
+        warning!("the styling of {} is not supported, drawn as a box", name);
+    }
+    wrapper.wrap("\\fbox{", "}");
+    wrapper
+}
+
+/// Horizontal rule, e.g. `#line(length: 100%, stroke: 1pt)` becomes `\\noindent\\rule{\\linewidth}{1pt}`.
+fn line(args: &Args) -> Option<Wrapper> {
+    let mut width = "\\linewidth".to_string();
+    let mut thickness = "0.4pt".to_string();
+    for (name, value) in &args.named {
+        match name.as_str() {
+            "length" => width = length_to_latex(value, "\\linewidth")?,
+            // The stroke may also hold a color, e.g. 1pt + red
+            "stroke" => {
+                if let Some(length) = value.split('+').find_map(|part| length_to_latex(part, "")) {
+                    thickness = length;
+                }
+            }
+            _ => {
+                warning!("unsupported line argument {:?}, left to pandoc", name);
+                return None;
+            }
+        }
+    }
+    Some(Wrapper {
+        open: format!("\\noindent\\rule{{{width}}}{{{thickness}}}"),
+        ..Default::default()
+    })
+}
+
+/// Latex of a `#context` expression, None if it cannot be converted statically.
+/// Only counter displays and lookups of labelled elements are handled, e.g.
+/// `counter(heading).at(<intro>).first()` becomes `\\ref{intro}`.
+pub fn context(expr: &str) -> Option<String> {
+    let expr: String = expr.chars().filter(|c| !c.is_whitespace()).collect();
+    match expr.as_str() {
+        "counter(page).display()" | "here().page()" => return Some("\\thepage".into()),
+        "counter(heading).display()" => return Some("\\thesection".into()),
+        _ => {}
+    }
+    let (_, rest) = expr.split_once("(<")?;
+    let (label, _) = rest.split_once(">)")?;
+    if expr.starts_with("counter(page)") || expr.ends_with(".page()") {
+        Some(format!("\\pageref{{{label}}}"))
+    } else if expr.starts_with("counter(") {
+        Some(format!("\\ref{{{label}}}"))
+    } else {
+        None
+    }
+}
+
+/// Quotation, with the quotation marks of the language through csquotes when it is set.
+fn quote(args: &Args, settings: &Settings) -> Wrapper {
+    let mut wrapper = Wrapper::default();
+    let mut block = false;
+    for (name, value) in &args.named {
+        match name.as_str() {
+            "block" => block = value == "true",
+            _ => wrapper.remaining.named.push((name.clone(), value.clone())),
+        }
+    }
+    match (block, settings.lang.is_some()) {
+        (true, true) => wrapper.wrap("\\begin{displayquote}", "\\end{displayquote}"),
+        (true, false) => wrapper.wrap("\\begin{quote}", "\\end{quote}"),
+        (false, true) => wrapper.wrap("\\enquote{", "}"),
+        (false, false) => wrapper.wrap("``", "''"),
+    }
+    if !wrapper.remaining.named.is_empty() {
+        warning!("only the block argument of quote is supported, the others are ignored");
+        wrapper.remaining = Args::default();
+    }
+    wrapper
+}
+
+/// Leaders filling the line, e.g. `#repeat[.]` becomes `\\dotfill`.
+fn repeat(body: &str) -> Wrapper {
+    let fill = match body.trim() {
+        "." => "\\dotfill",
+        "-" | "_" | "\u{2014}" | "\u{2013}" => "\\hrulefill",
+        _ => {
+            return Wrapper {
+                open: "\\leaders\\hbox{".into(),
+                close: "}\\hfill".into(),
+                ..Default::default()
+            };
+        }
+    };
+    Wrapper {
+        open: fill.into(),
+        drop_body: true,
+        ..Default::default()
+    }
+}
+
+/// Citation of a label, e.g. `#cite(<key>, form: "prose")` becomes `\\textcite{key}` with biblatex.
+fn cite(args: &Args, settings: &Settings) -> Option<Wrapper> {
+    let key = args
+        .positional
+        .first()?
+        .strip_prefix('<')?
+        .strip_suffix('>')?;
+    let mut form = None;
+    for (name, value) in &args.named {
+        match name.as_str() {
+            "form" => form = Some(unquote(value).unwrap_or(value)),
+            _ => warning!("unsupported citation argument {:?}, ignored", name),
+        }
+    }
+    let command = match form {
+        // Only listed in the bibliography
+        Some("none") => "nocite",
+        Some(form) if form != "normal" && !settings.biblatex => {
+            warning!(
+                "citation form {:?} requires --dialect biblatex, cited normally",
+                form
+            );
+            cite_command(None, settings)
+        }
+        _ => cite_command(form, settings),
+    };
+    Some(Wrapper {
+        open: format!("\\{command}{{{key}}}"),
+        ..Default::default()
+    })
+}
+
+/// Converts the call of function `name` with content `bodies`, None if it is left to pandoc.
+pub fn convert_func(
+    name: &str,
+    args: &Args,
+    bodies: &[&str],
+    settings: &Settings,
+) -> Option<Wrapper> {
+    match name {
+        "text" => Some(text(args)),
+        "line" => line(args),
+        // Converted here so that citations in the note are resolved like in the main text
+        "footnote" if args.named.is_empty() => Some(Wrapper {
+            open: "\\footnote{".into(),
+            close: "}".into(),
+            ..Default::default()
+        }),
+        // Converted here so that it nests in headings and other commands
+        "smallcaps" => Some(Wrapper {
+            open: "\\textsc{".into(),
+            close: "}".into(),
+            ..Default::default()
+        }),
+        "strong" => strong(args),
+        "quote" => Some(quote(args, settings)),
+        "cite" => cite(args, settings),
+        "pagebreak" => Some(pagebreak(args)),
+        "repeat" if args.named.is_empty() => Some(repeat(&bodies.concat())),
+        "block" => Some(block(args)),
+        "rect" | "circle" => Some(shape(name, args)),
+        "v" => spacing(args, "\\vspace", "\\textheight", "\\vfill"),
+        "h" => spacing(args, "\\hspace", "\\linewidth", "\\hfill"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(positional: &[&str], named: &[(&str, &str)]) -> Args {
+        Args {
+            positional: positional.iter().map(|p| p.to_string()).collect(),
+            named: named
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+        }
+    }
+
+    fn open(name: &str, args: &Args) -> String {
+        convert_func(name, args, &[], &Settings::stub())
+            .unwrap()
+            .open
+    }
+
+    #[test]
+    fn spacing_lengths() {
+        assert_eq!(open("v", &args(&["1.5em"], &[])), "\\vspace{1.5em}");
+        assert_eq!(open("v", &args(&["50%"], &[])), "\\vspace{0.5\\textheight}");
+        assert_eq!(
+            open("h", &args(&[], &[("amount", "25%")])),
+            "\\hspace{0.25\\linewidth}"
+        );
+        assert_eq!(open("h", &args(&["1fr"], &[])), "\\hfill");
+    }
+
+    #[test]
+    fn text_features() {
+        let wrapper = text(&args(&[], &[("features", "(\"smcp\", \"liga\")")]));
+        assert_eq!(
+            (wrapper.open.as_str(), wrapper.close.as_str()),
+            ("\\textsc{", "}")
+        );
+        let wrapper = text(&args(&[], &[("features", "(smcp: 0, onum: 1)")]));
+        assert_eq!(wrapper.open, "\\oldstylenums{");
+    }
+
+    #[test]
+    fn block_spacing() {
+        let wrapper = block(&args(&[], &[("above", "1em"), ("below", "2em")]));
+        assert_eq!(
+            (wrapper.open.as_str(), wrapper.close.as_str()),
+            ("\\vspace{1em}", "\\vspace{2em}")
+        );
+        let wrapper = block(&args(&[], &[]));
+        assert!(wrapper.open.is_empty() && wrapper.close.is_empty());
+    }
+
+    #[test]
+    fn strong_delta() {
+        assert_eq!(open("strong", &args(&[], &[("delta", "300")])), "\\textbf{");
+        assert_eq!(open("strong", &args(&[], &[("delta", "100")])), "\\textbf{");
+        assert_eq!(
+            open("strong", &args(&[], &[("delta", "-200")])),
+            "\\textbf{"
+        );
+        assert_eq!(open("strong", &args(&[], &[("delta", "0")])), "");
+        assert_eq!(open("strong", &args(&[], &[])), "\\textbf{");
+    }
+}
@@ -1,15 +1,24 @@
+mod args;
 mod bib_parser;
 mod converter;
 mod document;
+mod functions;
+mod math;
 mod pandoc;
+mod settings;
 mod text;
+mod warnings;
 
 use anyhow::{Context, Result};
 use bib_parser::parse_bib;
-use clap::{Arg, Command};
-use converter::parse;
+use clap::{Arg, ArgAction, Command};
+use converter::{CONSTRUCTS, SET_RULES, parse, trace_rules, unsupported_rules};
+use functions::FUNCTIONS;
+use math::{MATH_FUNCTIONS, parse_symbol_map};
+use pandoc::unmapped_math_chars;
+use settings::Settings;
 use std::{
-    collections::HashSet,
+    collections::{HashMap, HashSet},
     fs,
     path::{Path, PathBuf},
 };
@@ -29,6 +38,84 @@ fn typ2tex(path: &Path) -> PathBuf {
     }
 }
 
+/// Reads a file as UTF-8, reporting the position and surroundings of the first invalid byte.
+fn read_utf8(path: &Path) -> Result<String> {
+    let bytes = fs::read(path).with_context(|| format!("Failed to read file: {:?}", path))?;
+    String::from_utf8(bytes).map_err(|e| {
+        let bytes = e.as_bytes();
+        let offset = e.utf8_error().valid_up_to();
+        let line_start = bytes[..offset]
+            .iter()
+            .rposition(|&b| b == b'\n')
+            .map_or(0, |i| i + 1);
+        let line = bytes[..offset].iter().filter(|&&b| b == b'\n').count() + 1;
+        let column = String::from_utf8_lossy(&bytes[line_start..offset])
+            .chars()
+            .count()
+            + 1;
+        let context_end = bytes[offset..]
+            .iter()
+            .position(|&b| b == b'\n')
+            .map_or(bytes.len(), |i| offset + i);
+        anyhow::anyhow!(
+            "Invalid UTF-8 in file {:?} at byte {} (line {}, column {}): {:?}",
+            path,
+            offset,
+            line,
+            column,
+            String::from_utf8_lossy(&bytes[line_start..context_end])
+        )
+    })
+}
+
+/// Encodes the latex output, declaring the encoding to inputenc when the template loads it.
+fn encode(latex: &str, encoding: &str) -> Result<Vec<u8>> {
+    match encoding {
+        "latin1" => {
+            let latex = latex.replace(
+                "\\usepackage[utf8]{inputenc}",
+                "\\usepackage[latin1]{inputenc}",
+            );
+            let mut bytes = Vec::new();
+            for (index, line) in latex.split_inclusive('\n').enumerate() {
+                for c in line.chars() {
+                    let byte = u8::try_from(c as u32).map_err(|_| {
+                        anyhow::anyhow!(
+                            "Character {:?} (U+{:04X}) on line {} of the output cannot be encoded in latin1",
+                            c,
+                            c as u32,
+                            index + 1
+                        )
+                    })?;
+                    bytes.push(byte);
+                }
+            }
+            Ok(bytes)
+        }
+        _ => Ok(latex.as_bytes().to_vec()),
+    }
+}
+
+/// Prints the typst constructs that the conversion handles.
+fn print_features() {
+    println!("Constructs:");
+    for construct in CONSTRUCTS {
+        println!("  {construct}");
+    }
+    println!("Set rules:");
+    for (target, name) in SET_RULES {
+        println!("  #set {target}({name})");
+    }
+    println!("Functions:");
+    for name in FUNCTIONS {
+        println!("  #{name}");
+    }
+    println!("Math functions (the others are converted by pandoc):");
+    for name in MATH_FUNCTIONS {
+        println!("  {name}");
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum TemplateOption {
     Default,
@@ -55,13 +142,14 @@ fn main() -> Result<()> {
         .arg(
             Arg::new("input")
                 .help("The input typst file to parse")
-                .required(true),
+                .required_unless_present("list-features"),
         )
         .arg(
             Arg::new("bib")
                 .short('b')
                 .long("bib")
-                .help("A bib file for distinguishing citations and references"),
+                .action(ArgAction::Append)
+                .help("A bib file for distinguishing citations and references. Can be repeated"),
         )
         .arg(
             Arg::new("output")
@@ -84,18 +172,124 @@ fn main() -> Result<()> {
                 .value_parser(["pandoc"])
                 .default_value("pandoc"),
         )
+        .arg(
+            Arg::new("dialect")
+                .long("dialect")
+                .help("The citation commands to emit: \\cite, or the biblatex \\parencite and \\textcite")
+                .value_parser(["latex", "biblatex"])
+                .default_value("latex"),
+        )
+        .arg(
+            Arg::new("normalize-math")
+                .long("normalize-math")
+                .action(ArgAction::SetTrue)
+                .help("Normalize the spacing around scripts and braces in math"),
+        )
+        .arg(
+            Arg::new("output-encoding")
+                .long("output-encoding")
+                .help("The encoding of the output latex file")
+                .value_parser(["utf8", "latin1"])
+                .default_value("utf8"),
+        )
+        .arg(
+            Arg::new("minted")
+                .long("minted")
+                .action(ArgAction::SetTrue)
+                .help("Convert code blocks to minted environments with syntax highlighting"),
+        )
+        .arg(
+            Arg::new("preserve-raw-blocks")
+                .long("preserve-raw-blocks")
+                .action(ArgAction::SetTrue)
+                .overrides_with("no-preserve-raw-blocks")
+                .help("Copy code blocks verbatim, without pandoc (default)"),
+        )
+        .arg(
+            Arg::new("no-preserve-raw-blocks")
+                .long("no-preserve-raw-blocks")
+                .action(ArgAction::SetTrue)
+                .overrides_with("preserve-raw-blocks")
+                .help("Let pandoc convert the code blocks"),
+        )
+        .arg(
+            Arg::new("wrapper-env")
+                .long("wrapper-env")
+                .action(ArgAction::Append)
+                .value_name("NAME=ENV")
+                .help("The environment for calls to the function NAME defined by #let, quote by default. Can be repeated"),
+        )
+        .arg(
+            Arg::new("symbol-map")
+                .long("symbol-map")
+                .help("A file of math helpers, one \"name = latex\" per line with #1, #2... for the arguments, e.g. dv = \\frac{d #1}{d #2}"),
+        )
+        .arg(
+            Arg::new("emit-labels")
+                .long("emit-labels")
+                .value_name("FILE")
+                .help("Write the labels defined and the labels and citations referred to, tagged def or ref, one per line"),
+        )
+        .arg(
+            Arg::new("list-features")
+                .long("list-features")
+                .action(ArgAction::SetTrue)
+                .help("List the typst constructs that can be converted, then exit"),
+        )
+        .arg(
+            Arg::new("report-unsupported")
+                .long("report-unsupported")
+                .action(ArgAction::SetTrue)
+                .help("List the constructs that would be dropped, without converting"),
+        )
+        .arg(
+            Arg::new("trace-rules")
+                .long("trace-rules")
+                .action(ArgAction::SetTrue)
+                .help("Print the parse tree to stderr before converting, for debugging the grammar"),
+        )
+        .arg(
+            Arg::new("strict-math")
+                .long("strict-math")
+                .action(ArgAction::SetTrue)
+                .help("Fail if unicode characters remain in the converted math"),
+        )
+        .arg(
+            Arg::new("fail-on-warning")
+                .long("fail-on-warning")
+                .action(ArgAction::SetTrue)
+                .help("Fail if any warning is emitted during the conversion"),
+        )
         .get_matches();
 
+    if matches.get_flag("list-features") {
+        print_features();
+        return Ok(());
+    }
+
     let typst_path = Path::new(matches.get_one::<String>("input").unwrap());
     let template = matches.get_one::<TemplateOption>("template").unwrap();
-    let bib_path = matches.get_one::<String>("bib").map(Path::new);
+    let bib_paths = matches
+        .get_many::<String>("bib")
+        .unwrap_or_default()
+        .map(Path::new);
     let latex_path = matches
         .get_one::<&str>("output")
         .map_or(typ2tex(typst_path), PathBuf::from);
 
     // Read the typst file
-    let content = fs::read_to_string(typst_path)
-        .with_context(|| format!("Failed to read file: {:?}", typst_path))?;
+    let content = read_utf8(typst_path)?;
+
+    if matches.get_flag("report-unsupported") {
+        for (name, count) in unsupported_rules(&content)? {
+            println!("{count}\t{name}");
+        }
+        return Ok(());
+    }
+
+    if matches.get_flag("trace-rules") {
+        trace_rules(&content)?;
+    }
 
     // Read the latex template
     let template = match template {
@@ -106,20 +300,94 @@ fn main() -> Result<()> {
         }
     };
 
-    // Read the bib file
-    let citations = match bib_path {
-        Some(path) => parse_bib(
+    // Read the bib files
+    let mut citations = HashSet::<String>::new();
+    for path in bib_paths {
+        citations.extend(parse_bib(
             &fs::read_to_string(path)
-                .with_context(|| format!("Failed to read file: {:?}", bib_path))?,
-        ),
-        None => HashSet::<String>::new(),
+                .with_context(|| format!("Failed to read file: {:?}", path))?,
+        ));
+    }
+
+    let mut wrapper_envs = HashMap::new();
+    for mapping in matches
+        .get_many::<String>("wrapper-env")
+        .unwrap_or_default()
+    {
+        let (name, env) = mapping
+            .split_once('=')
+            .with_context(|| format!("Expected NAME=ENV for --wrapper-env, got {:?}", mapping))?;
+        wrapper_envs.insert(name.to_string(), env.to_string());
+    }
+
+    let math_helpers = match matches.get_one::<String>("symbol-map") {
+        Some(path) => parse_symbol_map(
+            &fs::read_to_string(path)
+                .with_context(|| format!("Failed to read file: {:?}", path))?,
+        )?,
+        None => HashMap::new(),
+    };
+
+    let settings = Settings {
+        citations,
+        wrapper_envs,
+        math_helpers,
+        biblatex: matches.get_one::<String>("dialect").unwrap() == "biblatex",
+        normalize_math: matches.get_flag("normalize-math"),
+        minted: matches.get_flag("minted"),
+        preserve_raw_blocks: !matches.get_flag("no-preserve-raw-blocks"),
+        ..Default::default()
     };
 
-    let document = parse(&content, citations)?;
+    let document = parse(&content, &settings)?;
+
+    if matches.get_flag("strict-math") {
+        let chars = unmapped_math_chars(&document.content);
+        if !chars.is_empty() {
+            let list: Vec<String> = chars
+                .iter()
+                .map(|c| format!("{} (U+{:04X})", c, *c as u32))
+                .collect();
+            anyhow::bail!(
+                "Unconverted unicode characters in math: {}",
+                list.join(", ")
+            );
+        }
+    }
+
+    let latex = document.to_latex(template)?;
+
+    if let Some(path) = matches.get_one::<String>("emit-labels") {
+        fs::write(path, document.labels())
+            .with_context(|| format!("Failed to write file: {:?}", path))?;
+    }
+
+    if matches.get_flag("fail-on-warning") && warnings::count() > 0 {
+        anyhow::bail!(
+            "{} warning(s) emitted during the conversion",
+            warnings::count()
+        );
+    }
 
     // Write the latex file
-    fs::write(&latex_path, document.to_latex(template))
+    let encoding = matches.get_one::<String>("output-encoding").unwrap();
+    fs::write(&latex_path, encode(&latex, encoding)?)
         .with_context(|| format!("Failed to write file: {:?}", latex_path))?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invalid_utf8_position() {
+        let path = std::env::temp_dir().join(format!("typst2latex-{}.typ", std::process::id()));
+        fs::write(&path, b"= Title\nAb\xffcd\n").unwrap();
+        let error = read_utf8(&path).unwrap_err().to_string();
+        fs::remove_file(&path).ok();
+        assert!(error.contains("at byte 10 (line 2, column 3)"), "{error}");
+        assert!(error.ends_with(": \"Ab\u{fffd}cd\""), "{error}");
+    }
+}
@@ -0,0 +1,555 @@
+use crate::args::color_to_latex;
+use crate::settings::Settings;
+use crate::text::{math_to_latex, placeholder};
+use crate::warnings::warning;
+use anyhow::{Result, bail};
+use std::collections::HashMap;
+
+// Preprocessing of typst math before pandoc. Some typst math functions are not (or badly) handled by pandoc,
+// those are converted to latex directly and replaced by a placeholder in the formula.
+
+/// Math functions converted by convert_call, the others being left to pandoc.
+pub const MATH_FUNCTIONS: [&str; 14] = [
+    "floor",
+    "ceil",
+    "round",
+    "mat",
+    "underbrace",
+    "overbrace",
+    "cases",
+    "vec",
+    "limits",
+    "scripts",
+    "cancel",
+    "attach",
+    "dd",
+    "diff",
+];
+
+/// Derivative helpers of the physics packages, whose `#n` stand for the converted arguments.
+/// The symbol map given by `--symbol-map` overrides them and adds others.
+const HELPERS: [(&str, &str); 2] = [
+    ("dd", "\\mathrm{d}#1"),
+    ("diff", "\\frac{\\partial #1}{\\partial #2}"),
+];
+
+/// Parses a symbol map, with a helper `name = latex` per line, e.g. `dv = \\frac{d #1}{d #2}`.
+/// Empty lines and lines starting with `%` are ignored.
+pub fn parse_symbol_map(content: &str) -> Result<HashMap<String, String>> {
+    let mut helpers = HashMap::new();
+    for (index, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('%') {
+            continue;
+        }
+        match line.split_once('=') {
+            Some((name, latex))
+                if !name.trim().is_empty()
+                    && name.trim().chars().all(|c| c.is_ascii_alphanumeric()) =>
+            {
+                helpers.insert(name.trim().to_string(), latex.trim().to_string());
+            }
+            _ => bail!(
+                "Expected name = latex on line {} of the symbol map, got {:?}",
+                index + 1,
+                line
+            ),
+        }
+    }
+    Ok(helpers)
+}
+
+/// Arguments of a math function call, e.g. `floor(x)` or `mat(1, 2; 3, 4, augment: 1)`.
+struct MathArgs<'a> {
+    positional: Vec<&'a str>,
+    named: Vec<(&'a str, &'a str)>,
+    /// Positional arguments split at the semicolons, e.g. the rows of a matrix.
+    rows: Vec<Vec<&'a str>>,
+}
+
+impl<'a> MathArgs<'a> {
+    fn parse(args: &'a str) -> Self {
+        let mut math_args = MathArgs {
+            positional: Vec::new(),
+            named: Vec::new(),
+            rows: vec![Vec::new()],
+        };
+        let mut depth = 0;
+        let mut in_string = false;
+        let mut start = 0;
+        for (i, c) in args.char_indices() {
+            match c {
+                '"' => in_string = !in_string,
+                _ if in_string => {}
+                '(' | '[' | '{' => depth += 1,
+                ')' | ']' | '}' => depth -= 1,
+                ',' | ';' if depth == 0 => {
+                    math_args.push(args[start..i].trim());
+                    if c == ';' {
+                        math_args.rows.push(Vec::new());
+                    }
+                    start = i + 1;
+                }
+                _ => {}
+            }
+        }
+        math_args.push(args[start..].trim());
+        math_args.rows.retain(|row| !row.is_empty());
+
+        math_args
+    }
+
+    fn push(&mut self, arg: &'a str) {
+        if arg.is_empty() {
+            return;
+        }
+        let named = arg.split_once(':').filter(|(name, _)| {
+            name.trim()
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        });
+        match named {
+            Some((name, value)) => self.named.push((name.trim(), value.trim())),
+            None => {
+                self.positional.push(arg);
+                if let Some(row) = self.rows.last_mut() {
+                    row.push(arg);
+                }
+            }
+        }
+    }
+
+    fn get(&self, name: &str) -> Option<&'a str> {
+        self.named
+            .iter()
+            .rev()
+            .find(|(n, _)| *n == name)
+            .map(|(_, value)| *value)
+    }
+}
+
+/// Latex delimiters of a typst delimiter argument, e.g. `"["` gives `\\left[` and `\\right]`.
+fn delimiters(delim: Option<&str>) -> Option<(&'static str, &'static str)> {
+    Some(
+        match delim.map(|d| d.trim_start_matches('#').trim_matches('"')) {
+            None | Some("(") => ("\\left(", "\\right)"),
+            Some("[") => ("\\left[", "\\right]"),
+            Some("{") => ("\\left\\{", "\\right\\}"),
+            Some("|") => ("\\left|", "\\right|"),
+            Some("||") => ("\\left\\|", "\\right\\|"),
+            Some("none") => ("", ""),
+            _ => return None,
+        },
+    )
+}
+
+/// Matrix with a vertical rule, e.g. `mat(1, 2; 3, 4, augment: 1)` gives an array `{c|c}`.
+/// The augment is either a column index, negative from the right, or a dictionary `(hline: 1, vline: 1)`.
+fn augmented_matrix(args: &MathArgs, augment: &str, settings: &Settings) -> Result<Option<String>> {
+    let columns = args.rows.iter().map(Vec::len).max().unwrap_or(0) as i64;
+    let index = |value: &str| {
+        value
+            .trim()
+            .parse::<i64>()
+            .ok()
+            .map(|i| if i < 0 { i + columns } else { i })
+    };
+    let (mut hline, mut vline) = (None, None);
+    let augment = augment.trim_start_matches('#');
+    if let Some(dict) = augment.strip_prefix('(').and_then(|a| a.strip_suffix(')')) {
+        for entry in dict.split(',') {
+            match entry.split_once(':').map(|(k, v)| (k.trim(), v)) {
+                Some(("hline", value)) => hline = index(value),
+                Some(("vline", value)) => vline = index(value),
+                _ => return Ok(None),
+            }
+        }
+    } else {
+        vline = index(augment);
+    }
+    let Some((left, right)) = delimiters(args.get("delim")) else {
+        return Ok(None);
+    };
+
+    let spec: String = (1..=columns)
+        .map(|i| {
+            if Some(i) == vline && i < columns {
+                "c|"
+            } else {
+                "c"
+            }
+        })
+        .collect();
+    let mut body = String::new();
+    for (i, row) in args.rows.iter().enumerate() {
+        if i > 0 {
+            body += " \\\\ ";
+        }
+        if hline == Some(i as i64) {
+            body += "\\hline ";
+        }
+        let cells = row
+            .iter()
+            .map(|cell| math_to_latex(cell, settings))
+            .collect::<Result<Vec<_>>>()?;
+        body += &cells.join(" & ");
+    }
+    Ok(Some(format!(
+        "{left}\\begin{{array}}{{{spec}}} {body} \\end{{array}}{right}"
+    )))
+}
+
+/// Cases with another delimiter than the left brace, e.g. `cases(delim: "[", ..)` gives `\\left[` around an array.
+/// The default brace is left to pandoc, which emits a cases environment.
+fn cases(args: &MathArgs, settings: &Settings) -> Result<Option<String>> {
+    let reverse = args.get("reverse").map(|r| r.trim_start_matches('#')) == Some("true");
+    let delim = args
+        .get("delim")
+        .map(|d| d.trim_start_matches('#').trim_matches('"'));
+    if !reverse && matches!(delim, None | Some("{")) {
+        return Ok(None);
+    }
+    let Some((left, right)) = delimiters(Some(delim.unwrap_or("{"))) else {
+        return Ok(None);
+    };
+    let (left, right) = match (left, reverse) {
+        ("", _) => ("", ""),
+        (_, false) => (left, "\\right."),
+        (_, true) => ("\\left.", right),
+    };
+
+    let mut rows = Vec::new();
+    for row in &args.positional {
+        let mut columns = Vec::new();
+        for column in split_alignment(row) {
+            columns.push(math_to_latex(column, settings)?);
+        }
+        rows.push(columns.join(" & "));
+    }
+    Ok(Some(format!(
+        "{left}\\begin{{array}}{{ll}} {} \\end{{array}}{right}",
+        rows.join(" \\\\ ")
+    )))
+}
+
+/// Splits a math row at its top-level alignment points, e.g. `x & "if" y` gives `x` and `"if" y`.
+fn split_alignment(row: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut start = 0;
+    for (i, c) in row.char_indices() {
+        match c {
+            '"' => in_string = !in_string,
+            _ if in_string => {}
+            '(' | '[' | '{' => depth += 1,
+            ')' | ']' | '}' => depth -= 1,
+            '&' if depth == 0 => {
+                parts.push(row[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(row[start..].trim());
+    parts
+}
+
+/// Column vector in a matrix environment of amsmath, e.g. `vec(x, y)` gives a pmatrix.
+/// The delimiter is given by the call or by `#set math.vec(delim: ...)`, parentheses by default.
+fn vector(args: &MathArgs, settings: &Settings) -> Result<Option<String>> {
+    if args.named.iter().any(|(name, _)| *name != "delim") {
+        return Ok(None);
+    }
+    let delim = args.get("delim").or(settings.vec_delim.as_deref());
+    let env = match delim.map(|d| d.trim_start_matches('#').trim_matches('"')) {
+        None | Some("(") => "pmatrix",
+        Some("[") => "bmatrix",
+        Some("{") => "Bmatrix",
+        Some("|") => "vmatrix",
+        Some("||") => "Vmatrix",
+        Some("none") => "matrix",
+        _ => return Ok(None),
+    };
+    let items = args
+        .positional
+        .iter()
+        .map(|item| math_to_latex(item, settings))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(Some(format!(
+        "\\begin{{{env}}} {} \\end{{{env}}}",
+        items.join(" \\\\ ")
+    )))
+}
+
+/// Color declaration of a cancel stroke, e.g. `red + 1pt` gives `\\color[HTML]{FF4136}`.
+/// The thickness of the stroke is not supported.
+pub fn cancel_color(stroke: &str) -> Option<String> {
+    let stroke = stroke.trim_start_matches('#');
+    let color = stroke
+        .split('+')
+        .find_map(|part| color_to_latex(part.trim()));
+    if stroke.split('+').count() > 1 || color.is_none() {
+        warning!(
+            "only the color of the cancel stroke {} is supported",
+            stroke
+        );
+    }
+    color.map(|color| format!("\\color{color}"))
+}
+
+/// Cancelation of a term, e.g. `cancel(x, stroke: red)` gives `\\cancel{x}` in red, the color being
+/// scoped to the term. Inverted and crossed cancelations give `\\bcancel` and `\\xcancel`.
+fn cancel(x: &str, args: &MathArgs, settings: &Settings) -> Result<String> {
+    let mut command = "cancel";
+    let mut color = None;
+    for (name, value) in &args.named {
+        match (*name, value.trim_start_matches('#')) {
+            ("inverted", "true") => command = "bcancel",
+            ("cross", "true") => command = "xcancel",
+            ("inverted" | "cross", _) => {}
+            ("stroke", stroke) => color = cancel_color(stroke),
+            _ => warning!("unsupported cancel argument {:?}, ignored", name),
+        }
+    }
+    let latex = format!("\\{command}{{{}}}", math_to_latex(x, settings)?);
+    Ok(match color {
+        Some(color) => format!("{{\\renewcommand{{\\CancelColor}}{{{color}}}{latex}}}"),
+        None => latex,
+    })
+}
+
+/// Scripts on the corners of a base, e.g. `attach(F, tl: n, bl: m)` gives `\\prescript{n}{m}{F}`.
+/// The top and bottom attachments are scripts, or above and below the base when there are also
+/// right scripts.
+fn attach(base: &str, args: &MathArgs, settings: &Settings) -> Result<Option<String>> {
+    let mut scripts = HashMap::new();
+    for (name, value) in &args.named {
+        if !matches!(*name, "t" | "b" | "tl" | "bl" | "tr" | "br") {
+            return Ok(None);
+        }
+        scripts.insert(*name, math_to_latex(value, settings)?);
+    }
+    let mut latex = math_to_latex(base, settings)?;
+    let right = scripts.contains_key("tr") || scripts.contains_key("br");
+    if right {
+        if let Some(t) = scripts.remove("t") {
+            latex = format!("\\overset{{{t}}}{{{latex}}}");
+        }
+        if let Some(b) = scripts.remove("b") {
+            latex = format!("\\underset{{{b}}}{{{latex}}}");
+        }
+    }
+    if scripts.contains_key("tl") || scripts.contains_key("bl") {
+        let tl = scripts.remove("tl").unwrap_or_default();
+        let bl = scripts.remove("bl").unwrap_or_default();
+        latex = format!("\\prescript{{{tl}}}{{{bl}}}{{{latex}}}");
+    }
+    if let Some(b) = scripts.remove("b").or(scripts.remove("br")) {
+        latex = format!("{{{latex}}}_{{{b}}}");
+    }
+    if let Some(t) = scripts.remove("t").or(scripts.remove("tr")) {
+        latex = format!("{latex}^{{{t}}}");
+    }
+    Ok(Some(latex))
+}
+
+/// Substitutes the converted arguments for `#1`, `#2`... in a helper, None if their number differs.
+fn helper(latex: &str, args: &MathArgs, settings: &Settings) -> Result<Option<String>> {
+    let arity = latex
+        .split('#')
+        .skip(1)
+        .filter_map(|rest| rest.chars().next()?.to_digit(10))
+        .max()
+        .unwrap_or(0) as usize;
+    if !args.named.is_empty() || args.positional.len() != arity {
+        return Ok(None);
+    }
+    let mut result = latex.to_string();
+    for (index, arg) in args.positional.iter().enumerate().rev() {
+        result = result.replace(&format!("#{}", index + 1), &math_to_latex(arg, settings)?);
+    }
+    Ok(Some(result))
+}
+
+/// Converts the function call `name(args)` to latex, or returns None if it should be left to pandoc.
+fn convert_call(name: &str, args: &MathArgs, settings: &Settings) -> Result<Option<String>> {
+    let helper_latex = settings
+        .math_helpers
+        .get(name)
+        .map(String::as_str)
+        .or(HELPERS
+            .iter()
+            .find(|(helper, _)| *helper == name)
+            .map(|(_, latex)| *latex));
+    if let Some(latex) = helper_latex {
+        return helper(latex, args, settings);
+    }
+    let latex = match (name, args.positional.as_slice()) {
+        ("floor", [x]) => format!("\\lfloor {} \\rfloor", math_to_latex(x, settings)?),
+        ("ceil", [x]) => format!("\\lceil {} \\rceil", math_to_latex(x, settings)?),
+        ("round", [x]) => format!("\\lfloor {} \\rceil", math_to_latex(x, settings)?),
+        ("mat", _) if let Some(augment) = args.get("augment") => {
+            return augmented_matrix(args, augment, settings);
+        }
+        ("underbrace", [x, label]) => format!(
+            "\\underbrace{{{}}}_{{{}}}",
+            math_to_latex(x, settings)?,
+            math_to_latex(label, settings)?
+        ),
+        ("overbrace", [x, label]) => format!(
+            "\\overbrace{{{}}}^{{{}}}",
+            math_to_latex(x, settings)?,
+            math_to_latex(label, settings)?
+        ),
+        ("cancel", [x]) => cancel(x, args, settings)?,
+        ("attach", [base]) => return attach(base, args, settings),
+        ("limits", [op]) => format!("{}\\limits", math_to_latex(op, settings)?),
+        ("scripts", [op]) => format!("{}\\nolimits", math_to_latex(op, settings)?),
+        ("cases", _) => return cases(args, settings),
+        ("vec", _) => return vector(args, settings),
+        _ => return Ok(None),
+    };
+    Ok(Some(latex))
+}
+
+/// Returns the index of the parenthesis closing the one opened just before `start`.
+fn closing_paren(math: &str, start: usize) -> Option<usize> {
+    let mut depth = 0;
+    let mut in_string = false;
+    for (i, c) in math[start..].char_indices() {
+        match c {
+            '"' => in_string = !in_string,
+            _ if in_string => {}
+            '(' => depth += 1,
+            ')' if depth == 0 => return Some(start + i),
+            ')' => depth -= 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Replaces the math function calls that pandoc does not handle by latex placeholders.
+pub fn rewrite_math(
+    math: &str,
+    settings: &Settings,
+    replacements: &mut Vec<String>,
+) -> Result<String> {
+    let mut result = String::new();
+    let mut rest = math;
+
+    while let Some(c) = rest.chars().next() {
+        // Strings are left untouched
+        if c == '"' {
+            let end = rest[1..].find('"').map_or(rest.len(), |i| i + 2);
+            result.push_str(&rest[..end]);
+            rest = &rest[end..];
+            continue;
+        }
+
+        let ident_len = rest
+            .find(|c: char| !c.is_ascii_alphanumeric())
+            .unwrap_or(rest.len());
+        if ident_len == 0 {
+            result.push(c);
+            rest = &rest[c.len_utf8()..];
+            continue;
+        }
+
+        let name = &rest[..ident_len];
+        let after = &rest[ident_len..];
+        let preceded_by_field = result.ends_with(['.', '#', '\\']);
+        if !preceded_by_field
+            && after.starts_with('(')
+            && let Some(end) = closing_paren(after, 1)
+            && let Some(latex) = convert_call(name, &MathArgs::parse(&after[1..end]), settings)?
+        {
+            result.push('"');
+            result.push_str(&placeholder(replacements, latex));
+            result.push('"');
+            rest = &after[end + 1..];
+        } else {
+            result.push_str(name);
+            rest = after;
+        }
+    }
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::text::{Text, to_latex};
+
+    fn math(typst: &str) -> String {
+        to_latex(vec![Text::Math(typst.into())], &Settings::stub()).unwrap()
+    }
+
+    #[test]
+    fn rounding() {
+        assert_eq!(math("$floor(x/2)$"), "\\(\\lfloor x/2 \\rfloor\\)");
+        assert_eq!(math("$ceil(x)$"), "\\(\\lceil x \\rceil\\)");
+        assert_eq!(math("$round(x)$"), "\\(\\lfloor x \\rceil\\)");
+    }
+
+    #[test]
+    fn augmented_system() {
+        assert_eq!(
+            math("$mat(1, 2, 3; 4, 5, 6, augment: #2)$"),
+            "\\(\\left(\\begin{array}{cc|c} 1 & 2 & 3 \\\\ 4 & 5 & 6 \\end{array}\\right)\\)"
+        );
+    }
+
+    #[test]
+    fn underbrace_annotation() {
+        assert_eq!(
+            math("$underbrace(a + b, n)$"),
+            "\\(\\underbrace{a + b}_{n}\\)"
+        );
+    }
+
+    #[test]
+    fn column_vector() {
+        assert_eq!(
+            math("$vec(x, y)$"),
+            "\\(\\begin{pmatrix} x \\\\ y \\end{pmatrix}\\)"
+        );
+    }
+
+    #[test]
+    fn partial_derivative() {
+        assert_eq!(
+            math("$diff(f, x)$"),
+            "\\(\\frac{\\partial f}{\\partial x}\\)"
+        );
+    }
+
+    #[test]
+    fn red_cancel() {
+        assert_eq!(
+            math("$cancel(x, stroke: red)$"),
+            "\\({\\renewcommand{\\CancelColor}{\\color[HTML]{FF4136}}\\cancel{x}}\\)"
+        );
+    }
+
+    #[test]
+    fn left_attachments() {
+        assert_eq!(
+            math("$attach(F, tl: n, bl: m)$"),
+            "\\(\\prescript{n}{m}{F}\\)"
+        );
+    }
+
+    #[test]
+    fn limits_and_scripts() {
+        assert_eq!(math("$scripts(sum)_i$"), "\\(sum\\nolimits_i\\)");
+        assert_eq!(math("$limits(A)_0^1 f$"), "\\(A\\limits_0^1 f\\)");
+        // The operator itself is converted by pandoc
+        if let Some(settings) = Settings::pandoc() {
+            let latex = to_latex(vec![Text::Math("$scripts(sum)_i$".into())], &settings).unwrap();
+            assert!(latex.contains("\\sum\\nolimits"), "{latex}");
+        }
+    }
+}
@@ -1,8 +1,31 @@
-use anyhow::{Context, Result};
-use std::process::Command;
+use anyhow::Result;
+
+/// Converter of the typst leaves to latex.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Backend {
+    #[default]
+    Pandoc,
+    /// Stands for pandoc in the unit tests, which do not require it to be installed.
+    #[cfg(test)]
+    Stub,
+}
+
+/// Converts Typst content to Latex using the backend, pandoc unless testing
+pub fn typst2latex(content: &str, backend: Backend) -> Result<String> {
+    let typst_output = match backend {
+        Backend::Pandoc => run_pandoc(content)?,
+        #[cfg(test)]
+        Backend::Stub => run_stub(content),
+    };
+
+    // Remove trailing newline that pandoc adds
+    Ok(apply_unicode2tex(typst_output.trim_end()))
+}
+
+fn run_pandoc(content: &str) -> Result<String> {
+    use anyhow::Context;
+    use std::process::Command;
 
-/// Converts Typst content to Latex using pandoc
-pub fn typst2latex(content: &str) -> Result<String> {
     // Create pandoc process
     let mut pandoc = Command::new("pandoc");
 
@@ -36,11 +59,165 @@ pub fn typst2latex(content: &str) -> Result<String> {
     }
 
     // Get the converted content
-    let typst_output =
-        String::from_utf8(output.stdout).context("Pandoc produced invalid UTF-8 output")?;
+    String::from_utf8(output.stdout).context("Pandoc produced invalid UTF-8 output")
+}
 
-    // Remove trailing newline that pandoc adds
-    Ok(apply_unicode2tex(typst_output.trim_end()))
+/// Only converts the math, as pandoc does for simple formulas, e.g. `$x "a"$` gives `\(x \text{a}\)`.
+#[cfg(test)]
+fn run_stub(content: &str) -> String {
+    let mut latex = String::new();
+    for (index, part) in content.split('$').enumerate() {
+        if index % 2 == 0 {
+            latex.push_str(part);
+            continue;
+        }
+        let math: String = part
+            .trim()
+            .split('"')
+            .enumerate()
+            .map(|(i, s)| {
+                if i % 2 == 1 {
+                    format!("\\text{{{s}}}")
+                } else {
+                    s.into()
+                }
+            })
+            .collect();
+        // Typst displays the formulas with spaces inside the dollars
+        if part.len() > 1
+            && part.starts_with(char::is_whitespace)
+            && part.ends_with(char::is_whitespace)
+        {
+            latex += &format!("\\[{math}\\]");
+        } else {
+            latex += &format!("\\({math}\\)");
+        }
+    }
+    latex
+}
+
+/// Byte ranges of the contents of the math spans \(...\) and \[...\] in latex output.
+fn math_spans(text: &str) -> Vec<(usize, usize)> {
+    let mut spans = Vec::new();
+    let mut offset = 0;
+
+    while let Some(start) = [text[offset..].find("\\("), text[offset..].find("\\[")]
+        .into_iter()
+        .flatten()
+        .min()
+    {
+        let start = offset + start + 2;
+        let close = if text[..start].ends_with('(') {
+            "\\)"
+        } else {
+            "\\]"
+        };
+        let Some(end) = text[start..].find(close).map(|i| start + i) else {
+            break;
+        };
+        spans.push((start, end));
+        offset = end + 2;
+    }
+    spans
+}
+
+/// Normalizes the spacing in the math spans of pandoc output, e.g. `x ^{2}` becomes `x^{2}`.
+/// Only whitespace that latex ignores is removed: control spaces and text inside \text are kept.
+pub fn normalize_math(text: &str) -> String {
+    let mut result = String::new();
+    let mut last = 0;
+    for (start, end) in math_spans(text) {
+        result.push_str(&text[last..start]);
+        result.push_str(&normalize_math_span(&text[start..end]));
+        last = end;
+    }
+    result.push_str(&text[last..]);
+    result
+}
+
+/// Non-ASCII characters left in math spans, which pdfLaTeX does not handle.
+pub fn unmapped_math_chars(text: &str) -> Vec<char> {
+    let mut chars = Vec::new();
+    for (start, end) in math_spans(text) {
+        for c in text[start..end].chars() {
+            if !c.is_ascii() && !chars.contains(&c) {
+                chars.push(c);
+            }
+        }
+    }
+    chars
+}
+
+fn normalize_math_span(math: &str) -> String {
+    const TEXT_COMMANDS: [&str; 6] = [
+        "\\text",
+        "\\textrm",
+        "\\textit",
+        "\\textbf",
+        "\\mbox",
+        "\\operatorname",
+    ];
+
+    let mut result = String::new();
+    let mut rest = math;
+
+    while let Some(c) = rest.chars().next() {
+        // Text arguments are copied verbatim, their spaces are significant
+        if let Some(command) = TEXT_COMMANDS
+            .iter()
+            .find(|cmd| rest.starts_with(*cmd) && rest[cmd.len()..].starts_with('{'))
+        {
+            let end = closing_brace(rest, command.len()).unwrap_or(rest.len());
+            result.push_str(&rest[..end]);
+            rest = &rest[end..];
+            continue;
+        }
+
+        // Escaped characters, including control spaces, are kept as is
+        if c == '\\' {
+            let escaped = rest[1..].chars().next().map_or(0, char::len_utf8);
+            result.push_str(&rest[..1 + escaped]);
+            rest = &rest[1 + escaped..];
+            continue;
+        }
+
+        if c.is_whitespace() {
+            let end = rest
+                .find(|c: char| !c.is_whitespace())
+                .unwrap_or(rest.len());
+            let before = result.chars().last();
+            let after = rest[end..].chars().next();
+            let removable = matches!(before, Some('^' | '_' | '{'))
+                || matches!(after, Some('^' | '_' | '{' | '}'));
+            if !removable {
+                result.push_str(&rest[..end]);
+            }
+            rest = &rest[end..];
+            continue;
+        }
+
+        result.push(c);
+        rest = &rest[c.len_utf8()..];
+    }
+
+    result
+}
+
+/// Returns the index just after the brace closing the one at `open`.
+fn closing_brace(text: &str, open: usize) -> Option<usize> {
+    let mut depth = 0;
+    let mut escaped = false;
+    for (i, c) in text[open..].char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '{' => depth += 1,
+            '}' if depth == 1 => return Some(open + i + 1),
+            '}' => depth -= 1,
+            _ => {}
+        }
+    }
+    None
 }
 
 // Postprocessing to fix pandoc output. Pandoc WILL output unicode character rather than math commands for the usual symbols, e.g. 𝛼 instead of \alpha.
@@ -123,3 +300,40 @@ fn unicode2tex(c: char) -> Option<&'static str> {
         _ => None,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::Settings;
+    use crate::text::{Text, to_latex};
+
+    #[test]
+    fn normalize_scripts_and_braces() {
+        assert_eq!(
+            normalize_math("\\(x ^{2} + y _ {i}\\)"),
+            "\\(x^{2} + y_{i}\\)"
+        );
+        assert_eq!(normalize_math("a ^ b \\(a ^ b\\)"), "a ^ b \\(a^b\\)");
+    }
+
+    #[test]
+    fn normalize_keeps_text_and_control_spaces() {
+        assert_eq!(
+            normalize_math("\\[\\text{a b} ^{2}\\]"),
+            "\\[\\text{a b}^{2}\\]"
+        );
+        assert_eq!(normalize_math("\\(a\\ {b}\\)"), "\\(a\\ {b}\\)");
+    }
+
+    #[test]
+    fn pandoc_placeholders() {
+        let Some(settings) = Settings::pandoc() else {
+            return;
+        };
+        let latex = to_latex(vec![Text::Math("$floor(x) + 1$".into())], &settings).unwrap();
+        assert!(latex.contains("\\lfloor x \\rfloor"), "{latex}");
+        // The placeholder is unwrapped from the \text pandoc puts it in
+        assert!(!latex.contains("\\text{"), "{latex}");
+        assert!(!latex.contains("identifier"), "{latex}");
+    }
+}
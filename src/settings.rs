@@ -0,0 +1,66 @@
+use crate::pandoc::Backend;
+use std::collections::{HashMap, HashSet};
+
+/// Options of the conversion, shared by the functions converting text to latex,
+/// together with the document-wide defaults changed by set rules.
+#[derive(Debug, Clone, Default)]
+pub struct Settings {
+    /// Converter of the typst leaves, pandoc by default.
+    pub backend: Backend,
+    /// Keys of the bib entries, used to distinguish citations from references.
+    pub citations: HashSet<String>,
+    /// Cite with the biblatex commands \\parencite and \\textcite instead of \\cite.
+    pub biblatex: bool,
+    /// Normalize the spacing in the math output of pandoc.
+    pub normalize_math: bool,
+    /// Emit code blocks as minted environments instead of verbatim.
+    pub minted: bool,
+    /// Environments of the functions defined by #let, by function name, instead of quote.
+    pub wrapper_envs: HashMap<String, String>,
+    /// Functions with a single argument defined by #let, whose calls become environments.
+    pub wrappers: HashSet<String>,
+    /// Emit code blocks verbatim rather than converting them with pandoc.
+    pub preserve_raw_blocks: bool,
+    /// Math helpers of the symbol map, e.g. `dd = \\mathrm{d}#1`, by function name.
+    pub math_helpers: HashMap<String, String>,
+    /// Page numbers are hidden by `#set page(numbering: none)`.
+    pub page_numbering_hidden: bool,
+    /// Float specifier of figures, set by `#set figure(placement: ...)`.
+    pub figure_placement: Option<String>,
+    /// Headings are unnumbered, set by presets such as `#show heading: it => it.body`.
+    pub unnumbered_headings: bool,
+    /// Headings are in small capitals, set by `#show heading: smallcaps`.
+    pub smallcaps_headings: bool,
+    /// Font series declaration of the text, set by `#set text(weight: ...)`.
+    pub font_series: Option<&'static str>,
+    /// Word spacing, set by `#set text(spacing: ...)`.
+    pub word_spacing: Option<String>,
+    /// Language of the text, set by `#set text(lang: ...)`.
+    pub lang: Option<String>,
+    /// Paragraph alignment declaration, set by `#set align(...)`.
+    pub alignment: Option<&'static str>,
+    /// Delimiter of math vectors, set by `#set math.vec(delim: ...)`.
+    pub vec_delim: Option<String>,
+    /// Tables are drawn without rules, set by `#set table(stroke: none)`.
+    pub table_borderless: bool,
+}
+
+#[cfg(test)]
+impl Settings {
+    /// Settings converting with the pandoc stand-in, for the unit tests.
+    pub fn stub() -> Self {
+        Settings {
+            backend: Backend::Stub,
+            ..Default::default()
+        }
+    }
+
+    /// Settings converting with pandoc, None if it is not installed.
+    pub fn pandoc() -> Option<Self> {
+        std::process::Command::new("pandoc")
+            .arg("--version")
+            .output()
+            .is_ok_and(|output| output.status.success())
+            .then(Settings::default)
+    }
+}
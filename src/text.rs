@@ -1,10 +1,12 @@
-use crate::pandoc::typst2latex;
+use crate::math::rewrite_math;
+use crate::pandoc::{normalize_math, typst2latex};
+use crate::settings::Settings;
 use anyhow::{Context, Result};
-use std::collections::HashSet;
 
 #[derive(Debug, Clone)]
 pub enum Text {
     Raw(String),
+    Math(String),
     Citation(String),
     Latex(String),
 }
@@ -13,16 +15,32 @@ fn unique_id(count: usize) -> String {
     format!("identifier{}identifier", count)
 }
 
-fn key_to_str(key: &str, citations: &HashSet<String>) -> String {
-    let clean_key = key.trim_start_matches('@');
+fn key_to_str(key: &str, settings: &Settings) -> String {
+    let citations = &settings.citations;
+    let cite = cite_command(None, settings);
+    // Merged citations hold several keys, e.g. "@a\n@b"
+    let keys: Vec<&str> = key
+        .split_whitespace()
+        .flat_map(|k| k.split('@'))
+        .filter(|k| !k.is_empty())
+        .collect();
 
     // Check for trailing space
     let has_trailing_space = key.ends_with(' ');
 
-    let citation = if citations.contains(clean_key.trim()) {
-        format!("\\cite{{{}}}", clean_key.trim())
+    let citation = if keys.iter().all(|k| citations.contains(*k)) {
+        format!("\\{cite}{{{}}}", keys.join(","))
     } else {
-        format!("\\autoref{{{}}}", clean_key.trim())
+        keys.iter()
+            .map(|k| {
+                if citations.contains(*k) {
+                    format!("\\{cite}{{{}}}", k)
+                } else {
+                    format!("\\autoref{{{}}}", k)
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
     };
 
     if has_trailing_space {
@@ -32,45 +50,111 @@ fn key_to_str(key: &str, citations: &HashSet<String>) -> String {
     }
 }
 
-pub fn to_latex(vec: Vec<Text>, citations: &HashSet<String>) -> Result<String> {
-    let id_string = build_id_string(&vec);
-    let mut latex_string = typst2latex(&id_string)
-        .with_context(|| format!("failed to convert to latex: {:?}", id_string))?;
+/// Citation command for a typst citation form, e.g. `"prose"` gives `textcite` with biblatex.
+/// Without biblatex, all forms are cited with \\cite.
+pub fn cite_command(form: Option<&str>, settings: &Settings) -> &'static str {
+    if !settings.biblatex {
+        return "cite";
+    }
+    match form {
+        Some("prose") => "textcite",
+        Some("author") => "citeauthor",
+        Some("year") => "citeyear",
+        Some("full") => "fullcite",
+        _ => "parencite",
+    }
+}
 
-    let mut count = 0;
-    for text in vec {
-        match text {
-            Text::Citation(key) => {
-                count += 1;
-                latex_string =
-                    latex_string.replace(&unique_id(count), &key_to_str(&key, citations));
+/// Registers a latex replacement and returns the identifier standing for it in the pandoc input.
+pub fn placeholder(replacements: &mut Vec<String>, latex: String) -> String {
+    replacements.push(latex);
+    unique_id(replacements.len())
+}
+
+/// Replaces the placeholders by their latex in a single pass, so that digits next to a placeholder
+/// are not mistaken for another one, e.g. in `identifier1identifier2identifier3identifier`.
+fn replace_placeholders(latex: &str, replacements: &[String]) -> String {
+    const ID: &str = "identifier";
+    let mut result = String::new();
+    let mut rest = latex;
+    while let Some(start) = rest.find(ID) {
+        result.push_str(&rest[..start]);
+        let after = &rest[start + ID.len()..];
+        let digits = after
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(after.len());
+        let replacement = after[..digits]
+            .parse::<usize>()
+            .ok()
+            .filter(|_| after[digits..].starts_with(ID))
+            .and_then(|index| replacements.get(index.wrapping_sub(1)));
+        match replacement {
+            Some(replacement) => {
+                rest = &after[digits + ID.len()..];
+                // Placeholders inside math are typst strings, which pandoc wraps in \text
+                if result.ends_with("\\text{") && rest.starts_with('}') {
+                    result.truncate(result.len() - "\\text{".len());
+                    rest = &rest[1..];
+                }
+                result.push_str(replacement);
             }
-            Text::Latex(content) => {
-                count += 1;
-                latex_string = latex_string.replace(&unique_id(count), &content);
+            None => {
+                result.push_str(ID);
+                rest = after;
             }
-
-            _ => (),
         }
     }
+    result.push_str(rest);
+    result
+}
+
+pub fn to_latex(vec: Vec<Text>, settings: &Settings) -> Result<String> {
+    let mut replacements = Vec::new();
+    let id_string = build_id_string(&vec, settings, &mut replacements)?;
+    let mut latex_string = typst2latex(&id_string, settings.backend)
+        .with_context(|| format!("failed to convert to latex: {:?}", id_string))?;
+
+    latex_string = replace_placeholders(&latex_string, &replacements);
+
+    if settings.normalize_math {
+        latex_string = normalize_math(&latex_string);
+    }
     Ok(latex_string)
 }
 
-fn build_id_string(vec: &Vec<Text>) -> String {
+/// Converts a typst math formula, without its dollars, to latex math.
+pub fn math_to_latex(math: &str, settings: &Settings) -> Result<String> {
+    let latex = to_latex(vec![Text::Math(format!("${}$", math))], settings)?;
+    Ok(latex
+        .trim()
+        .trim_start_matches("\\(")
+        .trim_end_matches("\\)")
+        .trim()
+        .to_string())
+}
+
+fn build_id_string(
+    vec: &Vec<Text>,
+    settings: &Settings,
+    replacements: &mut Vec<String>,
+) -> Result<String> {
     let mut result = String::new();
-    let mut count = 0;
 
     for text in vec {
         match text {
             Text::Raw(content) => {
-                result.push_str(&content);
+                result.push_str(content);
+            }
+            Text::Math(content) => {
+                result.push_str(&rewrite_math(content, settings, replacements)?);
+            }
+            Text::Citation(key) => {
+                result.push_str(&placeholder(replacements, key_to_str(key, settings)));
             }
-            Text::Citation(_) | Text::Latex(_) => {
-                count += 1;
-                let unique_id = unique_id(count);
-                result.push_str(&unique_id);
+            Text::Latex(content) => {
+                result.push_str(&placeholder(replacements, content.clone()));
             }
         }
     }
-    result
+    Ok(result)
 }
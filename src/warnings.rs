@@ -0,0 +1,25 @@
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Warnings about the parts of the document that are dropped or approximated. They all go through
+// warning! so that they are counted, e.g. for --fail-on-warning.
+
+static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+pub fn emit(message: fmt::Arguments) {
+    COUNT.fetch_add(1, Ordering::Relaxed);
+    eprintln!("Warning: {message}");
+}
+
+/// Number of warnings emitted so far.
+pub fn count() -> usize {
+    COUNT.load(Ordering::Relaxed)
+}
+
+/// Prints a warning on stderr and counts it, with the arguments of format!.
+macro_rules! warning {
+    ($($arg:tt)*) => {
+        $crate::warnings::emit(format_args!($($arg)*))
+    };
+}
+pub(crate) use warning;
@@ -0,0 +1,107 @@
+// Runs of the typst2latex binary on small documents. Pandoc is replaced by a stub echoing its
+// input, the tests checking the command line behaviour rather than the conversion.
+#![cfg(unix)]
+
+use std::env;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::process::{Command, Output};
+
+/// Converts `typst` with the stub pandoc and the arguments `args`, returning the output of the run
+/// and the Latex written, if any.
+fn run(name: &str, typst: &str, args: &[&str]) -> (Output, String) {
+    let dir = env::temp_dir().join(format!("typst2latex-cli-{}-{name}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let stub = dir.join("pandoc");
+    fs::write(&stub, "#!/bin/sh\ncat\n").unwrap();
+    fs::set_permissions(&stub, fs::Permissions::from_mode(0o755)).unwrap();
+    let input = dir.join("main.typ");
+    fs::write(&input, typst).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_typst2latex"))
+        .args(args)
+        .arg(&input)
+        .env(
+            "PATH",
+            format!("{}:{}", dir.display(), env::var("PATH").unwrap_or_default()),
+        )
+        .output()
+        .expect("failed to run typst2latex");
+    let latex = fs::read_to_string(dir.join("main.tex")).unwrap_or_default();
+    fs::remove_dir_all(&dir).ok();
+    (output, latex)
+}
+
+#[test]
+fn fail_on_warning() {
+    let (output, _) = run(
+        "unknown",
+        "#show link: underline\nText.\n",
+        &["--fail-on-warning"],
+    );
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("#show is not supported"), "{stderr}");
+    assert!(stderr.contains("1 warning(s) emitted"), "{stderr}");
+
+    let template = "#import \"@preview/unequivocal-ams:0.1.2\": ams-article, theorem, proof\n\n#show: ams-article.with(\n  title: [A Fluid Dynamic Model],\n  authors: (\n    (\n      name: \"Theresa Tungsten\",\n      organization: [Artos Institute],\n      location: [Tempe, AZ 85281],\n    ),\n  ),\n  abstract: [Some abstract.],\n)\n\n= Introduction\nSome text.\n";
+    let (output, _) = run("template", template, &["--fail-on-warning"]);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+#[test]
+fn preserve_raw_blocks_flag() {
+    let code = "```\n{x}\\\\\n```\n";
+    for flag in ["--preserve-raw-blocks", "--no-preserve-raw-blocks"] {
+        let (output, _) = run("raw", code, &[flag, "-t", "empty"]);
+        assert!(
+            output.status.success(),
+            "{flag}: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    let (_, latex) = run("raw", code, &["--preserve-raw-blocks", "-t", "empty"]);
+    assert!(
+        latex.contains("\\begin{verbatim}\n{x}\\\\\n\\end{verbatim}"),
+        "{latex}"
+    );
+}
+
+#[test]
+fn weak_pagebreak() {
+    let (output, latex) = run("weak", "Text.\n#pagebreak(weak: true)\n", &["-t", "empty"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("weak page breaks are not supported"),
+        "{stderr}"
+    );
+    assert!(latex.contains("\\clearpage"), "{latex}");
+}
+
+#[test]
+fn rtl_with_pdflatex_template() {
+    let (output, latex) = run("rtl", "#set text(dir: rtl)\nText.\n", &[]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("requires XeLaTeX"), "{stderr}");
+    assert!(latex.contains("\\setRTL"), "{latex}");
+
+    let template = env::temp_dir().join(format!("typst2latex-cli-{}.tex", std::process::id()));
+    fs::write(
+        &template,
+        "\\documentclass{article}\n\\usepackage{fontspec}\n%preamble%\n%content%\n",
+    )
+    .unwrap();
+    let (output, _) = run(
+        "xelatex",
+        "#set text(dir: rtl)\nText.\n",
+        &["-t", template.to_str().unwrap()],
+    );
+    fs::remove_file(&template).ok();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{stderr}");
+    assert!(!stderr.contains("requires XeLaTeX"), "{stderr}");
+}